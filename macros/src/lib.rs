@@ -0,0 +1,86 @@
+#![deny(missing_docs)]
+
+//! Attribute macros for [`stdio-override`](https://docs.rs/stdio-override).
+//!
+//! Use them through the `macros` feature of `stdio-override`, which re-exports them.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::parse::Parser;
+use syn::spanned::Spanned;
+use syn::{meta, FnArg, ItemFn, LitStr, ReturnType};
+
+/// Run a test with its standard output and error captured.
+///
+/// This replaces `#[test]`. The test may take a `&Capture` to look at the output captured so far,
+/// and `stdout = "..."` and `stderr = "..."` arguments assert what was captured once the body has
+/// run. If the body panics, what it printed is written to the original streams. See
+/// `stdio_override::capture` for details.
+#[proc_macro_attribute]
+pub fn capture(args: TokenStream, item: TokenStream) -> TokenStream {
+    match expand(args.into(), item.into()) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(args: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+    let mut stdout = None;
+    let mut stderr = None;
+    let parser = meta::parser(|meta| {
+        if meta.path.is_ident("stdout") {
+            stdout = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else if meta.path.is_ident("stderr") {
+            stderr = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `stdout` or `stderr`"))
+        }
+    });
+    parser.parse2(args)?;
+
+    let ItemFn { attrs, vis, sig, block } = syn::parse2(item)?;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(syn::Error::new(asyncness.span(), "captured tests can't be async"));
+    }
+    if sig.inputs.len() > 1 {
+        return Err(syn::Error::new(sig.inputs.span(), "captured tests take at most a `&Capture`"));
+    }
+    let capture = match sig.inputs.first() {
+        Some(FnArg::Typed(arg)) => {
+            let (pat, ty) = (&arg.pat, &arg.ty);
+            quote!(let #pat: #ty = &__capture;)
+        }
+        Some(FnArg::Receiver(receiver)) => return Err(syn::Error::new(receiver.span(), "captured tests can't take `self`")),
+        None => quote!(),
+    };
+    let output = match &sig.output {
+        ReturnType::Default => quote!(),
+        ReturnType::Type(arrow, ty) => quote!(#arrow #ty),
+    };
+    let assert_stdout =
+        stdout.map(|expected| quote_spanned!(expected.span()=> ::std::assert_eq!(#expected, __captured.stdout, "captured stdout");));
+    let assert_stderr =
+        stderr.map(|expected| quote_spanned!(expected.span()=> ::std::assert_eq!(#expected, __captured.stderr, "captured stderr");));
+    let checks = assert_stdout.is_some() || assert_stderr.is_some();
+    let (ident, generics) = (&sig.ident, &sig.generics);
+
+    Ok(quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #ident #generics() #output {
+            let __capture = ::stdio_override::macro_support::start();
+            let __result = {
+                #capture
+                (|| #output #block)()
+            };
+            if let ::std::option::Option::Some(__captured) = ::stdio_override::macro_support::finish(__capture, #checks) {
+                #assert_stdout
+                #assert_stderr
+            }
+            __result
+        }
+    })
+}
@@ -0,0 +1,258 @@
+use std::fmt::Write as _;
+
+use crate::transform::Transform;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// What to do with ANSI escape sequences in captured output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnsiMode {
+    /// Pass escape sequences through unchanged.
+    #[default]
+    Keep,
+    /// Remove all escape sequences, including CSI (colours, cursor movement) and OSC (titles,
+    /// hyperlinks) sequences.
+    Strip,
+    /// Escape the text as HTML and turn colour and style sequences into `<span>`s. Other escape
+    /// sequences are removed.
+    ToHtml,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    Csi,
+    Osc,
+    OscEscape,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    fg: Option<String>,
+    bg: Option<String>,
+}
+impl Style {
+    fn css(&self) -> String {
+        let mut css = String::new();
+        if self.bold {
+            css.push_str("font-weight:bold;");
+        }
+        if self.dim {
+            css.push_str("opacity:0.7;");
+        }
+        if self.italic {
+            css.push_str("font-style:italic;");
+        }
+        if self.underline {
+            css.push_str("text-decoration:underline;");
+        }
+        if let Some(fg) = &self.fg {
+            let _ = write!(css, "color:{};", fg);
+        }
+        if let Some(bg) = &self.bg {
+            let _ = write!(css, "background-color:{};", bg);
+        }
+        css
+    }
+    fn apply_sgr(&mut self, params: &[u8]) {
+        let params: Vec<u16> = params
+            .split(|&b| b == b';')
+            .map(|param| std::str::from_utf8(param).ok().and_then(|p| p.parse().ok()).unwrap_or(0))
+            .collect();
+        let mut params = params.into_iter();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.fg = Some(palette(param - 30)),
+                38 => self.fg = extended_color(&mut params),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(palette(param - 40)),
+                48 => self.bg = extended_color(&mut params),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(palette(param - 90 + 8)),
+                100..=107 => self.bg = Some(palette(param - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<String> {
+    match params.next()? {
+        5 => params.next().map(palette),
+        2 => {
+            let (r, g, b) = (params.next()?, params.next()?, params.next()?);
+            Some(format!("#{:02x}{:02x}{:02x}", r.min(255), g.min(255), b.min(255)))
+        }
+        _ => None,
+    }
+}
+
+/// The xterm 256 colour palette.
+fn palette(index: u16) -> String {
+    const BASIC: [&str; 16] = [
+        "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5", "#7f7f7f", "#ff0000", "#00ff00",
+        "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+    ];
+    match index {
+        0..=15 => BASIC[usize::from(index)].to_owned(),
+        16..=231 => {
+            let level = |n: u16| if n == 0 { 0 } else { 55 + n * 40 };
+            let n = index - 16;
+            format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6))
+        }
+        _ => {
+            let grey = 8 + (index.min(255) - 232) * 10;
+            format!("#{:02x}{:02x}{:02x}", grey, grey, grey)
+        }
+    }
+}
+
+/// A streaming transform that strips, keeps or converts ANSI escape sequences.
+///
+/// The parser keeps its state between chunks, so a sequence split across pipe reads is still
+/// recognized.
+/// ```rust
+/// use stdio_override::transform::{Ansi, AnsiMode, Transform};
+///
+/// let mut ansi = Ansi::new(AnsiMode::Strip);
+/// let mut output = Vec::new();
+/// ansi.transform(b"\x1b[1;3", &mut output);
+/// ansi.transform(b"1mred\x1b[0m", &mut output);
+/// assert_eq!(b"red", &output[..]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Ansi {
+    mode: AnsiMode,
+    state: State,
+    params: Vec<u8>,
+    style: Style,
+    span_open: bool,
+}
+impl Ansi {
+    /// Create a transform for the mode.
+    pub fn new(mode: AnsiMode) -> Self {
+        Self { mode, state: State::Ground, params: Vec::new(), style: Style::default(), span_open: false }
+    }
+    fn text(&mut self, byte: u8, output: &mut Vec<u8>) {
+        if self.mode != AnsiMode::ToHtml {
+            output.push(byte);
+            return;
+        }
+        match byte {
+            b'&' => output.extend_from_slice(b"&amp;"),
+            b'<' => output.extend_from_slice(b"&lt;"),
+            b'>' => output.extend_from_slice(b"&gt;"),
+            b'"' => output.extend_from_slice(b"&quot;"),
+            _ => output.push(byte),
+        }
+    }
+    fn csi(&mut self, final_byte: u8, output: &mut Vec<u8>) {
+        if self.mode != AnsiMode::ToHtml || final_byte != b'm' {
+            return;
+        }
+        self.style.apply_sgr(&self.params);
+        self.close_span(output);
+        let css = self.style.css();
+        if !css.is_empty() {
+            output.extend_from_slice(format!("<span style=\"{}\">", css).as_bytes());
+            self.span_open = true;
+        }
+    }
+    fn close_span(&mut self, output: &mut Vec<u8>) {
+        if self.span_open {
+            output.extend_from_slice(b"</span>");
+            self.span_open = false;
+        }
+    }
+}
+impl Transform for Ansi {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        if self.mode == AnsiMode::Keep {
+            output.extend_from_slice(input);
+            return;
+        }
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Ground, ESC) => State::Escape,
+                (State::Ground, _) => {
+                    self.text(byte, output);
+                    State::Ground
+                }
+                (State::Escape, b'[') => {
+                    self.params.clear();
+                    State::Csi
+                }
+                (State::Escape, b']') => State::Osc,
+                (State::Escape, 0x20..=0x2f) | (State::EscapeIntermediate, 0x20..=0x2f) => State::EscapeIntermediate,
+                (State::Escape, _) | (State::EscapeIntermediate, _) => State::Ground,
+                (State::Csi, 0x40..=0x7e) => {
+                    self.csi(byte, output);
+                    State::Ground
+                }
+                (State::Csi, _) => {
+                    self.params.push(byte);
+                    State::Csi
+                }
+                (State::Osc, BEL) => State::Ground,
+                (State::Osc, ESC) => State::OscEscape,
+                (State::Osc, _) => State::Osc,
+                (State::OscEscape, b'\\') => State::Ground,
+                (State::OscEscape, _) => State::Osc,
+            };
+        }
+    }
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        self.close_span(output);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(mode: AnsiMode, chunks: &[&[u8]]) -> String {
+        let mut ansi = Ansi::new(mode);
+        let mut output = Vec::new();
+        for chunk in chunks {
+            ansi.transform(chunk, &mut output);
+        }
+        ansi.finish(&mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_strip_split_sequences() {
+        let input: &[&[u8]] = &[b"a\x1b", b"[31", b"mb\x1b]0;ti", b"tle\x1b", b"\\c\x1b]8;;x\x07d\x1b(Be"];
+        assert_eq!("abcde", apply(AnsiMode::Strip, input));
+        assert_eq!(input.concat(), apply(AnsiMode::Keep, input).into_bytes());
+    }
+
+    #[test]
+    fn test_to_html() {
+        let input: &[&[u8]] = &[b"<\x1b[1;3", b"1mred\x1b[38;5;21mblue\x1b[0m & \x1b[4munder"];
+        assert_eq!(
+            "&lt;<span style=\"font-weight:bold;color:#cd0000;\">red</span>\
+             <span style=\"font-weight:bold;color:#0000ff;\">blue</span> &amp; \
+             <span style=\"text-decoration:underline;\">under</span>",
+            apply(AnsiMode::ToHtml, input)
+        );
+    }
+}
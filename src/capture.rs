@@ -0,0 +1,878 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use os_pipe::{PipeReader, PipeWriter};
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+use crate::ansi::{Ansi, AnsiMode};
+use crate::registry::{self, Stream};
+use crate::transform::Transform;
+use crate::{imp, lock, rust_print_is_captured, Relay, StderrOverride, StdoutOverride};
+
+/// What a capture does once it holds its maximum size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Keep the start of the output, and drop everything after it.
+    KeepHead,
+    /// Keep the end of the output, dropping the oldest output to make room.
+    KeepTail,
+    /// Keep the start of the output, and fail when the capture is finished.
+    Error,
+}
+
+#[derive(Debug, Default)]
+struct BufferState {
+    data: VecDeque<u8>,
+    /// How far into the stream the data starts, after dropping the oldest output.
+    start: u64,
+    limit: Option<(usize, Overflow)>,
+    dropped: u64,
+    /// Whether the relay has stopped writing to the buffer.
+    closed: bool,
+    /// The last sync marker whose preceding output has all been appended.
+    synced: u64,
+    echo: Option<Echo>,
+}
+/// Input sent to standard input, to be echoed into the buffer once it has been read.
+#[derive(Debug)]
+struct Echo {
+    /// A copy of the read end of standard input's pipe, to ask how much is still unread.
+    source: PipeReader,
+    /// What was sent and hasn't been echoed yet.
+    pending: VecDeque<u8>,
+    /// Where the echoed input is in the stream.
+    ranges: Vec<Range<u64>>,
+}
+
+impl BufferState {
+    /// Append the input that has been read since the last echo.
+    ///
+    /// Output is written after reading the input it responds to, so doing this before appending it
+    /// puts the echo in the right place.
+    fn echo_read_input(&mut self) {
+        let Some(mut echo) = self.echo.take() else { return };
+        if let Ok(unread) = imp::unread(&echo.source) {
+            let read = echo.pending.len().saturating_sub(unread);
+            if read > 0 {
+                let input: Vec<u8> = echo.pending.drain(..read).collect();
+                let start = self.start + self.data.len() as u64;
+                self.append(&input);
+                echo.ranges.push(start..start + read as u64);
+            }
+        }
+        self.echo = Some(echo);
+    }
+    /// Where `position` in the stream is in the data, moving it past anything dropped to make room.
+    fn offset(&self, position: &mut u64) -> usize {
+        *position = (*position).max(self.start);
+        (*position - self.start) as usize
+    }
+    fn append(&mut self, buf: &[u8]) {
+        let (max_size, overflow) = match self.limit {
+            Some(limit) => limit,
+            None => return self.data.extend(buf),
+        };
+        if overflow == Overflow::KeepTail {
+            let keep = &buf[buf.len().saturating_sub(max_size)..];
+            let excess = (self.data.len() + keep.len()).saturating_sub(max_size);
+            self.data.drain(..excess);
+            self.data.extend(keep);
+            let dropped = (excess + buf.len() - keep.len()) as u64;
+            self.start += dropped;
+            self.dropped += dropped;
+        } else {
+            let room = max_size.saturating_sub(self.data.len()).min(buf.len());
+            self.data.extend(&buf[..room]);
+            self.dropped += (buf.len() - room) as u64;
+        }
+    }
+}
+
+/// The in-memory destination of a captured stream.
+#[derive(Debug, Default)]
+pub(crate) struct Buffer {
+    state: Mutex<BufferState>,
+    /// Notified whenever data is appended, a sync marker arrives, or the buffer is closed.
+    changed: Condvar,
+    /// The last sync marker the relay has read, whose preceding output may not be appended yet.
+    marker_seen: AtomicU64,
+}
+impl Buffer {
+    pub(crate) fn new(limit: Option<(usize, Overflow)>) -> Self {
+        Self { state: Mutex::new(BufferState { limit, ..BufferState::default() }), ..Self::default() }
+    }
+    fn lock(&self) -> MutexGuard<'_, BufferState> {
+        // A panic while holding the lock cannot leave the bytes in an invalid state.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn contents(&self) -> (String, u64) {
+        let mut state = self.lock();
+        (String::from_utf8_lossy(state.data.make_contiguous()).into_owned(), state.dropped)
+    }
+    /// Wait until `ready` returns something, checking again whenever the buffer changes. Returns
+    /// `None` if the buffer is closed and `ready` still returns `None`.
+    fn wait_until<R>(&self, deadline: Option<Instant>, mut ready: impl FnMut(&BufferState) -> Option<R>) -> io::Result<Option<R>> {
+        let mut state = self.lock();
+        loop {
+            if let Some(ready) = ready(&state) {
+                return Ok(Some(ready));
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = match deadline {
+                None => self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for output"));
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+    }
+    /// Wait for the next line at or after `position` in the stream, and move `position` past it.
+    ///
+    /// Lines are returned without their line ending. Once the buffer is closed, any unterminated
+    /// output is the last line, and then there are no more.
+    fn next_line(&self, position: &mut u64, deadline: Option<Instant>) -> io::Result<Option<String>> {
+        self.wait_until(deadline, |state| {
+            let offset = state.offset(position);
+            let newline = state.data.range(offset..).position(|&byte| byte == b'\n');
+            if newline.is_none() && !(state.closed && offset < state.data.len()) {
+                return None;
+            }
+            let len = newline.unwrap_or(state.data.len() - offset);
+            let mut line: Vec<u8> = state.data.range(offset..offset + len).copied().collect();
+            *position += (len + newline.map_or(0, |_| 1)) as u64;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            Some(String::from_utf8_lossy(&line).into_owned())
+        })
+    }
+    /// Wait for `find` to find the end of a match in the stream at or after `position`, and move
+    /// `position` past it. Returns the output up to the end of the match, or `None` if the buffer is
+    /// closed first.
+    pub(crate) fn expect(
+        &self,
+        position: &mut u64,
+        find: &dyn Fn(&[u8]) -> Option<usize>,
+        deadline: Instant,
+    ) -> io::Result<Option<String>> {
+        self.wait_until(Some(deadline), |state| {
+            let offset = state.offset(position);
+            let pending: Vec<u8> = state.data.range(offset..).copied().collect();
+            let end = find(&pending)?;
+            *position += end as u64;
+            Some(String::from_utf8_lossy(&pending[..end]).into_owned())
+        })
+    }
+    /// Echo input read from `source`, when it is added with [`echo_sent`](Self::echo_sent).
+    pub(crate) fn echo_from(&self, source: PipeReader) {
+        self.lock().echo = Some(Echo { source, pending: VecDeque::new(), ranges: Vec::new() });
+    }
+    /// Send `input` to `stdin`, and echo it once it has been read.
+    ///
+    /// Each part of the input is written and added to the pending input with the buffer locked, so
+    /// it can't be read and answered before it is pending. The buffer is unlocked while waiting for
+    /// room in the pipe, so that what the code prints meanwhile can still be appended.
+    pub(crate) fn send_echoed(&self, stdin: &PipeWriter, mut input: &[u8]) -> io::Result<()> {
+        while !input.is_empty() {
+            let mut state = self.lock();
+            match imp::write_nonblocking(stdin, input) {
+                Ok(written) => {
+                    if let Some(echo) = &mut state.echo {
+                        echo.pending.extend(&input[..written]);
+                    }
+                    input = &input[written..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    drop(state);
+                    thread::sleep(SEND_INTERVAL);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// Echo whatever input has been read, and wake anyone waiting on the buffer if there was any.
+    pub(crate) fn echo_read_input(&self) {
+        let mut state = self.lock();
+        let len = state.data.len();
+        state.echo_read_input();
+        if state.data.len() != len {
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+    /// Everything in the buffer, and where the echoed input is in it.
+    pub(crate) fn with_echoes(&self) -> (Vec<u8>, Vec<Range<usize>>) {
+        let mut state = self.lock();
+        state.echo_read_input();
+        let (start, end) = (state.start, state.start + state.data.len() as u64);
+        // Echoes dropped to make room are left out.
+        let ranges = state.echo.iter().flat_map(|echo| &echo.ranges).filter(|range| range.end > start);
+        let ranges = ranges.map(|range| (range.start.max(start) - start) as usize..(range.end.min(end) - start) as usize).collect();
+        (state.data.iter().copied().collect(), ranges)
+    }
+    /// The output at or after `position`.
+    pub(crate) fn pending(&self, mut position: u64) -> String {
+        let state = self.lock();
+        let offset = state.offset(&mut position);
+        String::from_utf8_lossy(&state.data.range(offset..).copied().collect::<Vec<u8>>()).into_owned()
+    }
+    /// Write a new sync marker to `stream` after flushing it, and wait until the relay has
+    /// appended everything before it.
+    fn sync(&self, stream: &mut dyn Write, deadline: Instant) -> io::Result<()> {
+        static NEXT_MARKER: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_MARKER.fetch_add(1, Ordering::SeqCst);
+        stream.flush()?;
+        write!(stream, "{}{}{}", SyncMarker::START, id, SyncMarker::END as char)?;
+        stream.flush()?;
+        self.wait_until(Some(deadline), |state| (state.synced >= id).then_some(())).map(drop)
+    }
+    fn overflow_error(&self, stream: &str) -> io::Result<()> {
+        let state = self.lock();
+        match state.limit {
+            Some((max_size, Overflow::Error)) if state.dropped > 0 => {
+                Err(io::Error::other(format!("captured standard {} exceeded {} bytes", stream, max_size)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The relay sink that appends to a [`Buffer`].
+///
+/// It never fails, even once the buffer is full, so the relay always keeps draining the pipe.
+#[derive(Debug)]
+pub(crate) struct BufferSink(pub(crate) Arc<Buffer>);
+impl Write for BufferSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.lock();
+        state.echo_read_input();
+        state.append(buf);
+        drop(state);
+        self.0.changed.notify_all();
+        Ok(buf.len())
+    }
+    /// The relay flushes after writing out each chunk, so by now everything before the markers it
+    /// has read is appended.
+    fn flush(&mut self) -> io::Result<()> {
+        let seen = self.0.marker_seen.load(Ordering::SeqCst);
+        let mut state = self.0.lock();
+        if seen > state.synced {
+            state.synced = seen;
+            drop(state);
+            self.0.changed.notify_all();
+        }
+        Ok(())
+    }
+}
+impl Drop for BufferSink {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.echo_read_input();
+        state.closed = true;
+        drop(state);
+        self.0.changed.notify_all();
+    }
+}
+
+/// Strips the markers written by [`Capture::sync`] from a captured stream, and tells the buffer
+/// which it has read.
+///
+/// A marker is an operating system command escape sequence, which terminals ignore, holding its id.
+#[derive(Debug)]
+struct SyncMarker {
+    buffer: Weak<Buffer>,
+    /// The end of the last chunk, if it could be the start of a marker.
+    partial: Vec<u8>,
+}
+impl SyncMarker {
+    const START: &'static str = "\x1b]stdio-override-sync;";
+    const END: u8 = b'\x07';
+    fn new(buffer: &Arc<Buffer>) -> Self {
+        // Weak, as the relay outlives the capture when the guard is leaked.
+        Self { buffer: Arc::downgrade(buffer), partial: Vec::new() }
+    }
+    fn seen(&self, digits: &[u8]) {
+        let id = std::str::from_utf8(digits).ok().and_then(|id| id.parse().ok());
+        if let (Some(id), Some(buffer)) = (id, self.buffer.upgrade()) {
+            buffer.marker_seen.fetch_max(id, Ordering::SeqCst);
+        }
+    }
+}
+impl Transform for SyncMarker {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        let mut data = mem::take(&mut self.partial);
+        data.extend_from_slice(input);
+        let start = Self::START.as_bytes();
+        let mut rest = &data[..];
+        while let Some(at) = rest.windows(start.len()).position(|window| window == start) {
+            output.extend_from_slice(&rest[..at]);
+            let marker = &rest[at + start.len()..];
+            let digits = marker.iter().take_while(|byte| byte.is_ascii_digit()).count();
+            if digits > 0 && marker.get(digits) == Some(&Self::END) {
+                self.seen(&marker[..digits]);
+                rest = &marker[digits + 1..];
+            } else if digits == marker.len() && digits <= MAX_MARKER_DIGITS {
+                // The rest of the marker is in the next chunk.
+                self.partial = rest[at..].to_vec();
+                return;
+            } else {
+                output.extend_from_slice(start);
+                rest = marker;
+            }
+        }
+        // Hold back the longest end that could be the start of a marker.
+        let held = (1..start.len().min(rest.len() + 1)).rev().find(|&len| rest.ends_with(&start[..len])).unwrap_or(0);
+        output.extend_from_slice(&rest[..rest.len() - held]);
+        self.partial = rest[rest.len() - held..].to_vec();
+    }
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        output.append(&mut self.partial);
+    }
+}
+
+/// Output captured by a [`Capture`].
+///
+/// Invalid UTF-8 in the output is replaced with `U+FFFD`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captured {
+    /// Everything written to standard output, or an empty string if it was not captured.
+    pub stdout: String,
+    /// Everything written to standard error, or an empty string if it was not captured.
+    pub stderr: String,
+    /// How many bytes of standard output were dropped because the capture was full.
+    pub stdout_dropped: u64,
+    /// How many bytes of standard error were dropped because the capture was full.
+    pub stderr_dropped: u64,
+}
+impl Captured {
+    /// Whether any output was dropped because the capture was full.
+    pub fn truncated(&self) -> bool {
+        self.stdout_dropped > 0 || self.stderr_dropped > 0
+    }
+}
+
+/// Options for starting a [`Capture`].
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    stdout: bool,
+    stderr: bool,
+    ansi: AnsiMode,
+    limit: Option<(usize, Overflow)>,
+    strict: bool,
+    flush_c_stdio: bool,
+}
+impl CaptureOptions {
+    /// Whether to capture standard output. This is on by default.
+    pub fn stdout(mut self, capture: bool) -> Self {
+        self.stdout = capture;
+        self
+    }
+    /// Whether to capture standard error. This is on by default.
+    pub fn stderr(mut self, capture: bool) -> Self {
+        self.stderr = capture;
+        self
+    }
+    /// What to do with ANSI escape sequences. They are kept by default.
+    pub fn ansi(mut self, mode: AnsiMode) -> Self {
+        self.ansi = mode;
+        self
+    }
+    /// Limit each captured stream to `max_size` bytes, handling any more output as the overflow
+    /// policy says. Captures are unlimited by default.
+    ///
+    /// The pipe is still drained once the limit is hit, so writers never block.
+    pub fn max_size(mut self, max_size: usize, overflow: Overflow) -> Self {
+        self.limit = Some((max_size, overflow));
+        self
+    }
+    /// Whether to fail to start if `print!` and friends are intercepted by the test harness, which
+    /// happens when tests run without `--nocapture`. Off by default.
+    ///
+    /// Without this such a capture silently captures nothing. Output written with the macros in
+    /// [`print`](crate::print) is captured either way.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    /// Whether [`Capture::sync`] and [`Capture::finish`] also flush C's stdio buffers, for output
+    /// written with `printf` and the like. Off by default.
+    pub fn flush_c_stdio(mut self, flush: bool) -> Self {
+        self.flush_c_stdio = flush;
+        self
+    }
+    /// Start capturing.
+    #[track_caller]
+    pub fn start(self) -> io::Result<Capture> {
+        if self.strict && rust_print_is_captured()? {
+            return Err(io::Error::other(
+                "print! is captured by the test harness and would not reach the capture, run the tests with --nocapture",
+            ));
+        }
+        let held = lock::acquire("Capture")?;
+        let stdout = if self.stdout { Some(self.stream(Stream::Stdout, StdoutOverride::from_relay)?) } else { None };
+        let stderr = if self.stderr { Some(self.stream(Stream::Stderr, StderrOverride::from_relay)?) } else { None };
+        Ok(Capture {
+            stdout,
+            stderr,
+            #[cfg(feature = "regex")]
+            waited_for: AtomicU64::new(0),
+            flush_c_stdio: self.flush_c_stdio,
+            _held: held,
+        })
+    }
+    fn stream<G>(&self, stream: Stream, from_relay: fn(Relay) -> io::Result<G>) -> io::Result<(G, Arc<Buffer>)> {
+        let buffer = Arc::new(Buffer::new(self.limit));
+        // Markers are stripped first, so the other transforms never see them.
+        let relay = Relay::new(BufferSink(Arc::clone(&buffer))).transform(SyncMarker::new(&buffer)).transform(Ansi::new(self.ansi));
+        let guard = from_relay(relay)?;
+        live_buffers().push((stream, Arc::downgrade(&buffer)));
+        Ok((guard, buffer))
+    }
+}
+
+/// The buffers of every capture that has been started, so a panic hook can find them.
+static LIVE_BUFFERS: Mutex<Vec<(Stream, Weak<Buffer>)>> = Mutex::new(Vec::new());
+
+fn live_buffers() -> MutexGuard<'static, Vec<(Stream, Weak<Buffer>)>> {
+    let mut live = LIVE_BUFFERS.lock().unwrap_or_else(|e| e.into_inner());
+    live.retain(|(_, buffer)| buffer.strong_count() > 0);
+    live
+}
+
+/// The most digits a marker's id can have, those of `u64::MAX`.
+const MAX_MARKER_DIGITS: usize = 20;
+/// How often echoed input is sent again while standard input's pipe is full.
+const SEND_INTERVAL: Duration = Duration::from_millis(1);
+/// How long [`Capture::sync`] waits for its markers.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything captured so far by all the captures that are still alive, oldest capture first.
+pub(crate) fn captured_so_far() -> Captured {
+    let mut captured = Captured::default();
+    for (stream, buffer) in live_buffers().iter() {
+        let Some(buffer) = buffer.upgrade() else { continue };
+        let (contents, dropped) = buffer.contents();
+        if *stream == Stream::Stdout {
+            captured.stdout.push_str(&contents);
+            captured.stdout_dropped += dropped;
+        } else {
+            captured.stderr.push_str(&contents);
+            captured.stderr_dropped += dropped;
+        }
+    }
+    captured
+}
+
+/// Captures standard output and error in memory.
+///
+/// The streams are relayed into memory until [`finish`](Self::finish) is called, or until this is
+/// dropped.
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::{AnsiMode, Capture};
+///
+/// let capture = Capture::options().ansi(AnsiMode::Strip).start()?;
+/// println!("\x1b[32mgreen\x1b[0m");
+/// eprintln!("to stderr");
+/// let captured = capture.finish()?;
+///
+/// assert_eq!("green\n", captured.stdout);
+/// assert_eq!("to stderr\n", captured.stderr);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Capture {
+    stdout: Option<(StdoutOverride, Arc<Buffer>)>,
+    stderr: Option<(StderrOverride, Arc<Buffer>)>,
+    /// Where in the standard output [`wait_for`](Self::wait_for) carries on from.
+    #[cfg(feature = "regex")]
+    waited_for: AtomicU64,
+    flush_c_stdio: bool,
+    /// Released after the guards are reset.
+    _held: Option<lock::Held>,
+}
+impl Capture {
+    /// Options for a capture, which capture both streams and keep ANSI escape sequences by default.
+    pub fn options() -> CaptureOptions {
+        CaptureOptions { stdout: true, stderr: true, ansi: AnsiMode::Keep, limit: None, strict: false, flush_c_stdio: false }
+    }
+    /// Capture standard output only.
+    #[track_caller]
+    pub fn stdout() -> io::Result<Self> {
+        Self::options().stderr(false).start()
+    }
+    /// Capture standard error only.
+    #[track_caller]
+    pub fn stderr() -> io::Result<Self> {
+        Self::options().stdout(false).start()
+    }
+    /// Capture both standard output and standard error.
+    #[track_caller]
+    pub fn both() -> io::Result<Self> {
+        Self::options().start()
+    }
+    /// The output captured so far.
+    ///
+    /// Output that is still buffered by the writer, in the pipe, or in the relay is not included.
+    pub fn contents(&self) -> Captured {
+        let (stdout, stdout_dropped) = self.stdout.as_ref().map(|(_, buffer)| buffer.contents()).unwrap_or_default();
+        let (stderr, stderr_dropped) = self.stderr.as_ref().map(|(_, buffer)| buffer.contents()).unwrap_or_default();
+        Captured { stdout, stderr, stdout_dropped, stderr_dropped }
+    }
+    /// The lines of standard output, from the start of the capture, as they are written.
+    ///
+    /// The iterator blocks until the next line is complete, so it only ends once the capture is
+    /// finished, which needs another thread. It is empty if standard output isn't captured. Lines are
+    /// returned without their line endings, and lines dropped because the capture was full are
+    /// skipped.
+    /// ```rust
+    /// # fn main() -> std::io::Result<()> {
+    /// use stdio_override::Capture;
+    ///
+    /// let capture = Capture::stdout()?;
+    /// println!("first");
+    /// println!("second");
+    /// assert_eq!(vec!["first", "second"], capture.lines().take(2).collect::<Vec<_>>());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn lines(&self) -> CapturedLines<'_> {
+        CapturedLines { buffer: self.stdout.as_ref().map(|(_, buffer)| &**buffer), position: 0 }
+    }
+    /// Wait for a line of standard output that matches `regex`, and return it.
+    ///
+    /// The first call searches from the start of the capture, and later calls carry on after the
+    /// line the previous call returned. This fails with [`io::ErrorKind::TimedOut`] if no line
+    /// matches within `timeout`, or with [`io::ErrorKind::UnexpectedEof`] if standard output isn't
+    /// captured.
+    /// ```rust
+    /// # fn main() -> std::io::Result<()> {
+    /// use std::{thread, time::Duration};
+    /// use stdio_override::{Capture, Regex};
+    ///
+    /// let capture = Capture::stdout()?;
+    /// let server = thread::spawn(|| println!("listening on port 8080"));
+    /// let line = capture.wait_for(&Regex::new(r"listening on port \d+").unwrap(), Duration::from_secs(10))?;
+    /// assert_eq!("listening on port 8080", line);
+    /// # server.join().unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Needs the `regex` feature, which `redact` and `normalize` also enable.
+    #[cfg(feature = "regex")]
+    pub fn wait_for(&self, regex: &Regex, timeout: Duration) -> io::Result<String> {
+        let deadline = Instant::now() + timeout;
+        let mut position = self.waited_for.load(Ordering::SeqCst);
+        let buffer = self.stdout.as_ref().map(|(_, buffer)| buffer);
+        while let Some(line) = buffer.map(|buffer| buffer.next_line(&mut position, Some(deadline))).transpose()?.flatten() {
+            if regex.is_match(&line) {
+                self.waited_for.store(position, Ordering::SeqCst);
+                return Ok(line);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "standard output ended without a matching line"))
+    }
+    /// Wait until everything printed before this call has been captured, so that
+    /// [`contents`](Self::contents) includes it.
+    ///
+    /// Rust's standard output and error are flushed, and a marker is written to each captured
+    /// stream after what was flushed. This returns once the relays have read the markers, which
+    /// are never captured.
+    ///
+    /// It fails without writing anything if a captured stream has been overridden again since the
+    /// capture started, as the marker would end up in the newer override. It fails with
+    /// [`io::ErrorKind::TimedOut`] if a marker doesn't arrive within ten seconds.
+    /// ```rust
+    /// # fn main() -> std::io::Result<()> {
+    /// use stdio_override::Capture;
+    ///
+    /// let capture = Capture::stdout()?;
+    /// print!("so far");
+    /// capture.sync()?;
+    /// assert_eq!("so far", capture.contents().stdout);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sync(&self) -> io::Result<()> {
+        if self.flush_c_stdio {
+            imp::flush_c_stdio();
+        }
+        let overridden =
+            |stream: &str| io::Error::other(format!("standard {} was overridden again after the capture started", stream));
+        if matches!(&self.stdout, Some((guard, _)) if !registry::is_innermost(Stream::Stdout, guard.index)) {
+            return Err(overridden("output"));
+        }
+        if matches!(&self.stderr, Some((guard, _)) if !registry::is_innermost(Stream::Stderr, guard.index)) {
+            return Err(overridden("error"));
+        }
+        let deadline = Instant::now() + SYNC_TIMEOUT;
+        if let Some((_, buffer)) = &self.stdout {
+            buffer.sync(&mut io::stdout().lock(), deadline)?;
+        }
+        if let Some((_, buffer)) = &self.stderr {
+            buffer.sync(&mut io::stderr().lock(), deadline)?;
+        }
+        Ok(())
+    }
+    /// Stop capturing, resetting the streams, and return everything that was captured.
+    ///
+    /// Rust's standard output and error are flushed first, so nothing printed before this call is
+    /// missed. This fails if a stream overflowed a capture with [`Overflow::Error`].
+    pub fn finish(mut self) -> io::Result<Captured> {
+        if self.flush_c_stdio {
+            imp::flush_c_stdio();
+        }
+        let stdout = match self.stdout.take() {
+            Some((guard, buffer)) => {
+                io::stdout().flush()?;
+                guard.reset()?;
+                Some(buffer)
+            }
+            None => None,
+        };
+        let stderr = match self.stderr.take() {
+            Some((guard, buffer)) => {
+                io::stderr().flush()?;
+                guard.reset()?;
+                Some(buffer)
+            }
+            None => None,
+        };
+        let (stdout, stdout_dropped) = match stdout {
+            Some(buffer) => {
+                buffer.overflow_error("output")?;
+                buffer.contents()
+            }
+            None => Default::default(),
+        };
+        let (stderr, stderr_dropped) = match stderr {
+            Some(buffer) => {
+                buffer.overflow_error("error")?;
+                buffer.contents()
+            }
+            None => Default::default(),
+        };
+        Ok(Captured { stdout, stderr, stdout_dropped, stderr_dropped })
+    }
+}
+
+/// A blocking iterator over the lines of a capture's standard output, made by [`Capture::lines`].
+#[derive(Debug)]
+pub struct CapturedLines<'a> {
+    buffer: Option<&'a Buffer>,
+    position: u64,
+}
+impl Iterator for CapturedLines<'_> {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        // Waiting without a deadline can't time out.
+        self.buffer?.next_line(&mut self.position, None).ok()?
+    }
+}
+
+/// Run `f` with its standard output and error captured, and only show them if it fails.
+///
+/// If `f` returns `Ok` its output is thrown away. If it returns `Err` or panics, its output is
+/// written to where standard output and error went before, and then the error is returned or the
+/// panic resumed. This is much like the output capturing that `cargo test` does for tests.
+///
+/// If the streams cannot be captured, `f` is run with its output shown as usual.
+/// ```rust
+/// let res: Result<(), String> = stdio_override::quiet_unless_failure(|| {
+///     println!("this is never shown");
+///     Ok(())
+/// });
+/// assert!(res.is_ok());
+/// ```
+pub fn quiet_unless_failure<T, E, F: FnOnce() -> Result<T, E>>(f: F) -> Result<T, E> {
+    let capture = match Capture::both() {
+        Ok(capture) => capture,
+        Err(_) => return f(),
+    };
+    let res = panic::catch_unwind(AssertUnwindSafe(f));
+    if let Ok(Ok(_)) = res {
+        let _ = capture.finish();
+    } else {
+        let _ = capture.replay();
+    }
+    match res {
+        Ok(res) => res,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+impl Capture {
+    /// Finish the capture, and write what it captured to the original streams.
+    pub(crate) fn replay(self) -> io::Result<()> {
+        let stdout = self.stdout.as_ref().map(|(guard, _)| guard.original.try_clone()).transpose()?;
+        let stderr = self.stderr.as_ref().map(|(guard, _)| guard.original.try_clone()).transpose()?;
+        let captured = self.finish()?;
+        let write = |original: Option<File>, output: &str| original.map_or(Ok(()), |mut file| file.write_all(output.as_bytes()));
+        write(stdout, &captured.stdout)?;
+        write(stderr, &captured.stderr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture_html() -> io::Result<()> {
+        let capture = Capture::options().stderr(false).ansi(AnsiMode::ToHtml).start()?;
+        print!("<b>\x1b[31mred\x1b[0m");
+        let captured = capture.finish()?;
+
+        assert_eq!("&lt;b&gt;<span style=\"color:#cd0000;\">red</span>", captured.stdout);
+        assert_eq!("", captured.stderr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_overflow() {
+        let tail = Buffer::new(Some((4, Overflow::KeepTail)));
+        let head = Buffer::new(Some((4, Overflow::KeepHead)));
+        let error = Buffer::new(Some((4, Overflow::Error)));
+        for buffer in [&tail, &head, &error] {
+            for chunk in [&b"abc"[..], b"defgh", b"ij"] {
+                buffer.lock().append(chunk);
+            }
+        }
+        assert_eq!(("ghij".to_owned(), 6), tail.contents());
+        assert_eq!(("abcd".to_owned(), 6), head.contents());
+        assert!(head.overflow_error("output").is_ok());
+        assert!(error.overflow_error("output").is_err());
+    }
+
+    #[test]
+    fn test_capture_max_size() -> io::Result<()> {
+        let capture = Capture::options().stderr(false).max_size(8, Overflow::KeepTail).start()?;
+        for i in 0..10_000 {
+            println!("{}", i);
+        }
+        let captured = capture.finish()?;
+
+        assert_eq!("98\n9999\n", captured.stdout);
+        assert!(captured.truncated());
+        assert_eq!((0..10_000).map(|i| i.to_string().len() as u64 + 1).sum::<u64>() - 8, captured.stdout_dropped);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet_unless_failure() -> io::Result<()> {
+        let outer = Capture::both()?;
+        let ok: Result<u8, ()> = quiet_unless_failure(|| {
+            println!("quiet");
+            Ok(1)
+        });
+        let err: Result<(), u8> = quiet_unless_failure(|| {
+            println!("loud");
+            eprintln!("error");
+            Err(2)
+        });
+        let panicked = panic::catch_unwind(|| {
+            let _: Result<(), ()> = quiet_unless_failure(|| panic!("panicked"));
+        });
+        let captured = outer.finish()?;
+
+        assert_eq!((Ok(1), Err(2)), (ok, err));
+        assert!(panicked.is_err());
+        assert_eq!("loud\n", captured.stdout);
+        assert!(captured.stderr.starts_with("error\n"));
+        assert!(captured.stderr.contains("panicked"));
+        Ok(())
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_live_lines() -> io::Result<()> {
+        let capture = Capture::stdout()?;
+        let server = std::thread::spawn(|| {
+            println!("starting");
+            std::thread::sleep(Duration::from_millis(50));
+            print!("listening on port 1234\r\nrequest 1\n");
+            println!("request 2");
+        });
+        let port = Regex::new(r"listening on port (\d+)").unwrap();
+        assert_eq!("listening on port 1234", capture.wait_for(&port, Duration::from_secs(10))?);
+        // Later calls carry on after the line the last one returned.
+        let request = Regex::new("request").unwrap();
+        assert_eq!("request 1", capture.wait_for(&request, Duration::from_secs(10))?);
+        assert_eq!("request 2", capture.wait_for(&request, Duration::from_secs(10))?);
+        let timed_out = capture.wait_for(&port, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, timed_out.kind());
+        assert_eq!(vec!["starting", "listening on port 1234", "request 1"], capture.lines().take(3).collect::<Vec<_>>());
+        server.join().unwrap();
+
+        let captured = capture.finish()?;
+        assert_eq!("starting\nlistening on port 1234\r\nrequest 1\nrequest 2\n", captured.stdout);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_marker() {
+        let buffer = Arc::new(Buffer::new(None));
+        let mut marker = SyncMarker::new(&buffer);
+        let input = b"before\x1b]stdio-override-sync;42\x07after \x1b]stdio-override-sync;x\x07 \x1b]stdio";
+        // Every way of splitting the input in two gives the same output.
+        for split in 0..input.len() {
+            let mut output = Vec::new();
+            marker.transform(&input[..split], &mut output);
+            marker.transform(&input[split..], &mut output);
+            marker.finish(&mut output);
+            assert_eq!(&b"beforeafter \x1b]stdio-override-sync;x\x07 \x1b]stdio"[..], &output[..], "split at {}", split);
+        }
+        assert_eq!(42, buffer.marker_seen.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_sync() -> io::Result<()> {
+        let capture = Capture::both()?;
+        for i in 0..100 {
+            print!("{} ", i);
+            eprint!("{}", i);
+            capture.sync()?;
+            let contents = capture.contents();
+            assert!(contents.stdout.ends_with(&format!("{} ", i)), "{:?}", contents.stdout);
+            assert!(contents.stderr.ends_with(&i.to_string()));
+        }
+        let captured = capture.finish()?;
+        assert!(!captured.stdout.contains("sync"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_overridden_again() -> io::Result<()> {
+        let capture = Capture::stdout()?;
+        let (inner, mut reader) = StdoutOverride::pipe()?;
+        assert!(capture.sync().is_err());
+        inner.reset()?;
+        let mut written = Vec::new();
+        io::Read::read_to_end(&mut reader, &mut written)?;
+        assert!(written.is_empty(), "{:?}", written);
+
+        print!("after");
+        capture.sync()?;
+        assert_eq!("after", capture.contents().stdout);
+        Ok(())
+    }
+}
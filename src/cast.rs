@@ -0,0 +1,306 @@
+//! Recording sessions as [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) files,
+//! and replaying them.
+//!
+//! A [`Recorder`] writes a header line, then one line for each chunk of output or input, holding
+//! the time since the recording started, `"o"` or `"i"`, and the text. Its [`output`](Recorder::output)
+//! sinks go in the [`Relay`](crate::Relay)s of standard output and error, and
+//! [`stdin_from`](Recorder::stdin_from) feeds standard input from a reader, recording what it reads.
+//! The result can be played with `asciinema play`, or with [`replay`].
+//!
+//! Streams are overridden with pipes rather than a terminal, so programs see no terminal, and the
+//! width and height in the header are only what players are told to use.
+//!
+//! Needs the `cast` feature.
+//! ```rust
+//! # fn main() -> std::io::Result<()> {
+//! use std::fs::{self, File};
+//! use std::io;
+//! use stdio_override::cast::{self, Recorder};
+//! use stdio_override::{Relay, StdoutOverride};
+//!
+//! let file_name = "./demo.cast";
+//! let recorder = Recorder::new(File::create(file_name)?)?;
+//! let guard = StdoutOverride::from_relay(Relay::new(recorder.output()))?;
+//! println!("Hello, asciinema!");
+//! guard.reset()?;
+//!
+//! let mut replayed = Vec::new();
+//! cast::replay(io::BufReader::new(File::open(file_name)?), &mut replayed, f64::INFINITY)?;
+//! assert_eq!(b"Hello, asciinema!\n", &replayed[..]);
+//! # fs::remove_file(file_name)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::StdinOverride;
+
+/// Options for starting a [`Recorder`].
+#[derive(Debug, Clone)]
+pub struct RecorderOptions {
+    width: u16,
+    height: u16,
+    title: Option<String>,
+}
+impl RecorderOptions {
+    /// The terminal size players should use. This is 80 by 24 by default.
+    pub fn size(mut self, width: u16, height: u16) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+    /// The title of the recording. There is none by default.
+    pub fn title<T: Into<String>>(mut self, title: T) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+    /// Write the header to `writer`, and start recording to it.
+    pub fn start<W: Write + Send + 'static>(self, mut writer: W) -> io::Result<Recorder> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let mut header =
+            format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}", self.width, self.height, timestamp);
+        if let Some(title) = &self.title {
+            header.push_str(", \"title\": ");
+            header.push_str(&serde_json::to_string(title)?);
+        }
+        header.push_str("}\n");
+        writer.write_all(header.as_bytes())?;
+        writer.flush()?;
+        Ok(Recorder { inner: Arc::new(Mutex::new(Inner { writer: Box::new(writer), start: Instant::now() })) })
+    }
+}
+
+struct Inner {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+/// Records output and input, with their timings, in the asciicast v2 format.
+///
+/// Clones record to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<Inner>>,
+}
+impl Recorder {
+    /// Options for a recorder, which is 80 by 24 and untitled by default.
+    pub fn options() -> RecorderOptions {
+        RecorderOptions { width: 80, height: 24, title: None }
+    }
+    /// Start recording to `writer` with the default options.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        Self::options().start(writer)
+    }
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // A panic while holding the lock at worst leaves a line half written.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn event(&self, kind: EventKind, data: &str) -> io::Result<()> {
+        let mut inner = self.lock();
+        let line = format!("[{:.6}, \"{}\", {}]\n", inner.start.elapsed().as_secs_f64(), kind, serde_json::to_string(data)?);
+        inner.writer.write_all(line.as_bytes())?;
+        inner.writer.flush()
+    }
+    /// A sink that records everything written to it as output.
+    pub fn output(&self) -> CastSink {
+        CastSink { recorder: self.clone(), kind: EventKind::Output, partial: Vec::new() }
+    }
+    /// Override standard input with a pipe fed from `source` on a thread, recording what is read
+    /// from it as input.
+    ///
+    /// The pipe is closed once `source` ends, so standard input reads the end of the file.
+    #[track_caller]
+    pub fn stdin_from<R: Read + Send + 'static>(&self, mut source: R) -> io::Result<StdinOverride> {
+        let (guard, mut stdin) = StdinOverride::pipe()?;
+        let mut sink = CastSink { recorder: self.clone(), kind: EventKind::Input, partial: Vec::new() };
+        thread::Builder::new().name("stdio-override cast input".into()).spawn(move || {
+            let mut chunk = [0; 4096];
+            loop {
+                let len = match source.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return,
+                };
+                // Recorded first, so the input comes before any output it causes.
+                if sink.write_all(&chunk[..len]).is_err() || stdin.write_all(&chunk[..len]).is_err() {
+                    return;
+                }
+            }
+        })?;
+        Ok(guard)
+    }
+}
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").field("start", &self.lock().start).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Output,
+    Input,
+}
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::Output => "o",
+            EventKind::Input => "i",
+        })
+    }
+}
+
+/// A sink that records what is written to it, made by [`Recorder::output`].
+///
+/// Events hold text, so a UTF-8 sequence split between writes is held back until the rest of it
+/// arrives, and invalid UTF-8 is replaced with `U+FFFD`.
+#[derive(Debug)]
+pub struct CastSink {
+    recorder: Recorder,
+    kind: EventKind,
+    /// The end of the last write, if it is the start of a UTF-8 sequence.
+    partial: Vec<u8>,
+}
+impl Write for CastSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let complete = match str::from_utf8(&self.partial) {
+            Ok(_) => self.partial.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.partial.len(),
+        };
+        if complete > 0 {
+            let text: Vec<u8> = self.partial.drain(..complete).collect();
+            self.recorder.event(self.kind, &String::from_utf8_lossy(&text))?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.recorder.lock().writer.flush()
+    }
+}
+impl Drop for CastSink {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            let _ = self.recorder.event(self.kind, &String::from_utf8_lossy(&self.partial));
+        }
+    }
+}
+
+/// Write the output events of the asciicast v2 recording in `cast` to `output`, as they were timed.
+///
+/// `speed` scales the timings: 1 replays in real time, 2 twice as fast, and infinity without waiting.
+/// Events other than output are skipped, and the header is only checked to be that of a version 2
+/// recording. To replay to the original standard output while it is overridden, write to the
+/// [`StdoutOverride`](crate::StdoutOverride).
+pub fn replay<R: BufRead, W: Write>(cast: R, mut output: W, speed: f64) -> io::Result<()> {
+    let start = Instant::now();
+    for (number, line) in cast.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message: &dyn fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {} of the cast: {}", number + 1, message))
+        };
+        if number == 0 {
+            let header: serde_json::Value = serde_json::from_str(&line).map_err(|e| invalid(&e))?;
+            match header.get("version").and_then(serde_json::Value::as_u64) {
+                Some(2) => continue,
+                _ => return Err(invalid(&"not an asciicast v2 header")),
+            }
+        }
+        let (time, kind, data): (f64, String, String) = serde_json::from_str(&line).map_err(|e| invalid(&e))?;
+        if kind != "o" {
+            continue;
+        }
+        let due = Duration::try_from_secs_f64(time / speed).unwrap_or(Duration::ZERO);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+        output.write_all(data.as_bytes())?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A writer whose output can be read while it is being recorded to.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record() -> io::Result<()> {
+        let cast = Shared::default();
+        let recorder = Recorder::options().size(100, 30).title("a \"demo\"").start(cast.clone())?;
+        let stdin = recorder.stdin_from(io::Cursor::new("typed\n"))?;
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        stdin.reset()?;
+
+        let mut output = recorder.output();
+        // A character split between writes is recorded whole.
+        output.write_all(b"you typed: \xc3")?;
+        output.write_all(b"\xa9\n")?;
+        drop(output);
+
+        let cast = String::from_utf8(cast.0.lock().unwrap().clone()).unwrap();
+        let mut lines = cast.lines();
+        let header = lines.next().unwrap();
+        assert!(header.starts_with(r#"{"version": 2, "width": 100, "height": 30, "timestamp": "#), "{}", header);
+        assert!(header.ends_with(r#", "title": "a \"demo\""}"#), "{}", header);
+        let events: Vec<(f64, String, String)> = lines.map(|line| serde_json::from_str(line).unwrap()).collect();
+        let kinds_and_data: Vec<_> = events.iter().map(|(_, kind, data)| (kind.as_str(), data.as_str())).collect();
+        assert_eq!(vec![("i", "typed\n"), ("o", "you typed: "), ("o", "é\n")], kinds_and_data);
+        assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> io::Result<()> {
+        let cast = "{\"version\": 2, \"width\": 80, \"height\": 24}\n\
+                    [0.0, \"o\", \"first\\n\"]\n\
+                    [0.05, \"i\", \"ignored\"]\n\
+                    [0.2, \"o\", \"second\\u001b[0m\\n\"]\n";
+        let mut output = Vec::new();
+        let start = Instant::now();
+        replay(cast.as_bytes(), &mut output, 2.0)?;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(&b"first\nsecond\x1b[0m\n"[..], &output[..]);
+
+        let error = replay("{\"version\": 2}\n[0.0, \"o\"]\n".as_bytes(), io::sink(), 1.0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().starts_with("line 2 of the cast: "), "{}", error);
+        let error = replay("{\"version\": 3}\n".as_bytes(), io::sink(), 1.0).unwrap_err();
+        assert_eq!("line 1 of the cast: not an asciicast v2 header", error.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_asciinema() -> io::Result<()> {
+        // Recorded by asciinema 3.2 with `--output-format asciicast-v2`.
+        let cast = include_str!("../tests/data/asciinema.cast");
+        let mut output = Vec::new();
+        replay(cast.as_bytes(), &mut output, f64::INFINITY)?;
+        assert_eq!("héllo \x1b[1mbold\x1b[0m\r\ntab\there 😀 \"quoted\" done\r\n", String::from_utf8(output).unwrap());
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::thread;
+
+use crate::{Relay, Sink, StderrOverride, StdoutOverride};
+
+/// A fixed size buffer that keeps the most recent bytes written to it.
+///
+/// Clones share the same buffer. It can be read without taking any locks, which is what lets a
+/// [`CrashRing`] dump it from a signal handler.
+#[derive(Clone)]
+pub struct RingBuffer {
+    ring: Arc<Ring>,
+}
+struct Ring {
+    bytes: Box<[AtomicU8]>,
+    /// The total number of bytes ever written.
+    written: AtomicUsize,
+}
+impl RingBuffer {
+    /// Create a ring buffer that keeps the last `capacity` bytes written to it.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "ring buffers must have a capacity");
+        let bytes = (0..capacity).map(|_| AtomicU8::new(0)).collect();
+        Self { ring: Arc::new(Ring { bytes, written: AtomicUsize::new(0) }) }
+    }
+    /// The most recent bytes written, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+        self.ring.for_each_segment(&mut [0; 4096], |segment| contents.extend_from_slice(segment));
+        contents
+    }
+}
+impl Ring {
+    /// Pass the contents to `f` one segment at a time, using only async-signal-safe operations.
+    fn for_each_segment(&self, segment: &mut [u8], mut f: impl FnMut(&[u8])) {
+        let capacity = self.bytes.len();
+        let end = self.written.load(Ordering::Acquire);
+        let mut position = end.saturating_sub(capacity);
+        while position < end {
+            let len = (end - position).min(segment.len());
+            for (i, byte) in segment[..len].iter_mut().enumerate() {
+                *byte = self.bytes[(position + i) % capacity].load(Ordering::Relaxed);
+            }
+            f(&segment[..len]);
+            position += len;
+        }
+    }
+}
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let capacity = self.ring.bytes.len();
+        let len = buf.len();
+        let buf = &buf[len.saturating_sub(capacity)..];
+        // Reserving the space first lets several relays write at once. A reader may see the space
+        // before it is filled, which is an acceptable loss for a crash dump.
+        let start = self.ring.written.fetch_add(buf.len(), Ordering::AcqRel);
+        for (i, &byte) in buf.iter().enumerate() {
+            self.ring.bytes[(start + i) % capacity].store(byte, Ordering::Relaxed);
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl fmt::Debug for RingBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RingBuffer")
+            .field("capacity", &self.ring.bytes.len())
+            .field("written", &self.ring.written.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// What the hooks dump when the process crashes.
+struct CrashState {
+    ring: Arc<Ring>,
+    path: PathBuf,
+    #[cfg(unix)]
+    c_path: std::ffi::CString,
+}
+
+static CRASH_STATE: AtomicPtr<CrashState> = AtomicPtr::new(ptr::null_mut());
+/// How many hooks are using the crash state.
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+fn set_crash_state(state: Option<CrashState>) {
+    let state = state.map_or(ptr::null_mut(), |state| Box::into_raw(Box::new(state)));
+    let previous = CRASH_STATE.swap(state, Ordering::SeqCst);
+    if !previous.is_null() {
+        // Hooks that loaded the previous state before the swap have to be done with it first.
+        while IN_USE.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        drop(unsafe { Box::from_raw(previous) });
+    }
+}
+
+/// Call `f` with the crash state, if there is one, using only async-signal-safe operations.
+fn with_crash_state(f: impl FnOnce(&CrashState)) {
+    IN_USE.fetch_add(1, Ordering::SeqCst);
+    if let Some(state) = unsafe { CRASH_STATE.load(Ordering::SeqCst).as_ref() } {
+        f(state);
+    }
+    IN_USE.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Keeps standard output and error going to where they went before, while also keeping the last
+/// few kilobytes of both in a [`RingBuffer`]. If the program panics, or on Unix is killed by
+/// `SIGSEGV`, `SIGBUS` or `SIGABRT`, the contents of the ring are written to a crash file.
+///
+/// The previously installed panic hook and signal handlers still run, after the crash file is
+/// written. Only one crash ring should be active at a time; installing another replaces the first
+/// one's crash file.
+///
+/// Output reaches the ring through a relay thread, so the last writes before a crash may not be in
+/// it yet.
+///
+/// The signal handlers run on the thread's alternate signal stack, so that a stack overflow can be
+/// dumped too. Threads started by Rust have one, and installing gives the calling thread one if it
+/// doesn't.
+/// ```rust,no_run
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::CrashRing;
+///
+/// let crash_ring = CrashRing::install("./crash.log", 64 * 1024)?;
+/// println!("this goes to the terminal, and to crash.log if we crash");
+/// crash_ring.reset()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CrashRing {
+    installed: Installed,
+    stdout: StdoutOverride,
+    stderr: StderrOverride,
+    ring: RingBuffer,
+}
+impl CrashRing {
+    /// Start keeping the last `capacity` bytes of output, to be written to `crash_file` on a crash.
+    ///
+    /// This fails with [`io::ErrorKind::InvalidInput`] if `capacity` is zero.
+    pub fn install<P: AsRef<Path>>(crash_file: P, capacity: usize) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "crash rings must have a capacity"));
+        }
+        let ring = RingBuffer::new(capacity);
+        let path = crash_file.as_ref().to_path_buf();
+        #[cfg(unix)]
+        let c_path = std::ffi::CString::new(path_bytes(&path)).map_err(io::Error::other)?;
+
+        install_hooks();
+        #[cfg(unix)]
+        signal::ensure_alt_stack();
+        let stdout = StdoutOverride::from_relay(Relay::fan_out(vec![Sink::new(ring.clone())]).tee())?;
+        let stderr = StderrOverride::from_relay(Relay::fan_out(vec![Sink::new(ring.clone())]).tee())?;
+        set_crash_state(Some(CrashState {
+            ring: Arc::clone(&ring.ring),
+            path,
+            #[cfg(unix)]
+            c_path,
+        }));
+        Ok(Self { installed: Installed, stdout, stderr, ring })
+    }
+    /// The ring buffer the output is kept in.
+    pub fn ring(&self) -> &RingBuffer {
+        &self.ring
+    }
+    /// Stop keeping output, and reset standard output and error.
+    pub fn reset(self) -> io::Result<()> {
+        let Self { installed, stdout, stderr, .. } = self;
+        drop(installed);
+        let res = stdout.reset();
+        res.and(stderr.reset())
+    }
+}
+
+/// Clears the crash state when the crash ring goes away.
+#[derive(Debug)]
+struct Installed;
+impl Drop for Installed {
+    fn drop(&mut self) {
+        set_crash_state(None);
+    }
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+fn install_hooks() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            with_crash_state(|state| {
+                let _ = write_panic(state, info);
+            });
+        }));
+        #[cfg(unix)]
+        signal::install();
+    });
+}
+
+fn write_panic(state: &CrashState, info: &PanicHookInfo) -> io::Result<()> {
+    let mut file = fs::File::create(&state.path)?;
+    let mut res = Ok(());
+    state.ring.for_each_segment(&mut [0; 4096], |segment| {
+        if res.is_ok() {
+            res = file.write_all(segment);
+        }
+    });
+    res?;
+    writeln!(file, "\n{}", info)
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::cell::UnsafeCell;
+    use std::mem::MaybeUninit;
+    use std::ptr;
+
+    use libc::c_int;
+
+    const SIGNALS: [c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGABRT];
+
+    /// The handlers that were installed before ours. They are written once, before our handlers are
+    /// installed, and only read by our handlers afterwards.
+    struct Previous(UnsafeCell<[MaybeUninit<libc::sigaction>; 3]>);
+    unsafe impl Sync for Previous {}
+    static PREVIOUS: Previous = Previous(UnsafeCell::new([MaybeUninit::uninit(); 3]));
+
+    pub(super) fn install() {
+        for (i, &signal) in SIGNALS.iter().enumerate() {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_fatal_signal as extern "C" fn(c_int) as libc::sighandler_t;
+                libc::sigemptyset(&mut action.sa_mask);
+                action.sa_flags = libc::SA_ONSTACK;
+                let previous = (*PREVIOUS.0.get())[i].as_mut_ptr();
+                libc::sigaction(signal, &action, previous);
+            }
+        }
+    }
+
+    /// Give the calling thread an alternate signal stack if it doesn't have one yet. It stays the
+    /// thread's for as long as the thread runs, so it is never freed.
+    pub(super) fn ensure_alt_stack() {
+        const ALT_STACK_SIZE: usize = 64 * 1024;
+        unsafe {
+            let mut current: libc::stack_t = std::mem::zeroed();
+            if libc::sigaltstack(ptr::null(), &mut current) != 0 || current.ss_flags & libc::SS_DISABLE == 0 {
+                return;
+            }
+            let stack = Box::leak(vec![0u8; ALT_STACK_SIZE].into_boxed_slice());
+            let alt_stack = libc::stack_t { ss_sp: stack.as_mut_ptr().cast(), ss_flags: 0, ss_size: ALT_STACK_SIZE };
+            libc::sigaltstack(&alt_stack, ptr::null_mut());
+        }
+    }
+
+    /// Write the crash file using only async-signal-safe calls, then hand the signal to the handler
+    /// that was there before us.
+    extern "C" fn on_fatal_signal(signal: c_int) {
+        super::with_crash_state(|state| unsafe {
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+            let fd = libc::open(state.c_path.as_ptr(), flags, 0o644 as libc::c_uint);
+            if fd >= 0 {
+                state.ring.for_each_segment(&mut [0; 512], |segment| {
+                    let _ = libc::write(fd, segment.as_ptr().cast(), segment.len());
+                });
+                libc::close(fd);
+            }
+        });
+        if let Some(i) = SIGNALS.iter().position(|&s| s == signal) {
+            unsafe {
+                libc::sigaction(signal, (*PREVIOUS.0.get())[i].as_ptr(), ptr::null_mut());
+                libc::raise(signal);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() -> io::Result<()> {
+        let mut ring = RingBuffer::new(5);
+        ring.write_all(b"abc")?;
+        assert_eq!(b"abc", &ring.contents()[..]);
+        ring.write_all(b"defg")?;
+        assert_eq!(b"cdefg", &ring.contents()[..]);
+        ring.write_all(b"0123456789")?;
+        assert_eq!(b"56789", &ring.contents()[..]);
+
+        let error = CrashRing::install("./crash.log", 0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_crash_file_on_abort() -> io::Result<()> {
+        let crash_file = std::env::temp_dir().join(format!("stdio-override-crash-{}.log", std::process::id()));
+        let crash_ring = CrashRing::install(&crash_file, 16)?;
+        writeln!(io::stdout(), "some output before the crash")?;
+        io::stdout().flush()?;
+        while !crash_ring.ring().contents().ends_with(b"crash\n") {
+            std::thread::yield_now();
+        }
+
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => unsafe {
+                libc::raise(libc::SIGABRT);
+                libc::_exit(0);
+            },
+            child => {
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFSIGNALED(status));
+                assert_eq!(libc::SIGABRT, libc::WTERMSIG(status));
+            }
+        }
+        crash_ring.reset()?;
+
+        assert_eq!("efore the crash\n", fs::read_to_string(&crash_file)?);
+        fs::remove_file(&crash_file)
+    }
+
+    /// Run `f` in a child with its standard output going to a pipe and its standard error to
+    /// `/dev/null`, and return the child's output, its exit status and its crash file.
+    #[cfg(unix)]
+    fn in_child(name: &str, f: fn(&Path)) -> io::Result<(String, libc::c_int, String)> {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let crash_file = std::env::temp_dir().join(format!("stdio-override-{}-{}.log", name, std::process::id()));
+        let (mut reader, writer) = os_pipe::pipe()?;
+        let null = fs::OpenOptions::new().write(true).open("/dev/null")?;
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => unsafe {
+                libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+                f(&crash_file);
+                libc::_exit(0);
+            },
+            child => {
+                drop(writer);
+                let mut output = String::new();
+                reader.read_to_string(&mut output)?;
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                let crashed = fs::read_to_string(&crash_file)?;
+                fs::remove_file(&crash_file)?;
+                Ok((output, status, crashed))
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn print_into_ring(crash_file: &Path) -> CrashRing {
+        let crash_ring = CrashRing::install(crash_file, 64 * 1024).unwrap();
+        // Not println!, which libtest would capture.
+        writeln!(io::stdout(), "before the crash").unwrap();
+        io::stdout().flush().unwrap();
+        while !crash_ring.ring().contents().ends_with(b"crash\n") {
+            std::thread::yield_now();
+        }
+        crash_ring
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_crash_file_on_panic() -> io::Result<()> {
+        let (output, status, crashed) = in_child("panic", |crash_file| {
+            let _crash_ring = print_into_ring(crash_file);
+            let _ = panic::catch_unwind(|| panic!("the panic message"));
+        })?;
+        // The output still reaches the original standard output.
+        assert_eq!("before the crash\n", output);
+        assert!(libc::WIFEXITED(status));
+        assert!(crashed.starts_with("before the crash\n"), "{}", crashed);
+        assert!(crashed.contains("the panic message"), "{}", crashed);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_crash_file_on_stack_overflow() -> io::Result<()> {
+        fn recurse(depth: u64) -> u64 {
+            let frame = std::hint::black_box([depth; 64]);
+            if frame[0] == u64::MAX {
+                return 0;
+            }
+            recurse(frame[0] + 1) + frame[1]
+        }
+        let (output, status, crashed) = in_child("overflow", |crash_file| {
+            let _crash_ring = print_into_ring(crash_file);
+            recurse(0);
+        })?;
+        assert_eq!("before the crash\n", output);
+        assert!(libc::WIFSIGNALED(status));
+        assert!(crashed.starts_with("before the crash\n"), "{}", crashed);
+        Ok(())
+    }
+}
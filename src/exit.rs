@@ -0,0 +1,212 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use libc::c_int;
+use os_pipe::PipeReader;
+
+use crate::registry;
+use crate::relay;
+
+/// How long to wait for relays to drain before giving up.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(1);
+const SIGNALS: [c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// The write end of the pipe the signal handler wakes the restoring thread through.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Put the original standard streams back when the process exits or is killed, even if the guards
+/// are never dropped.
+///
+/// This registers an `atexit` hook, and handlers for `SIGINT` and `SIGTERM`. When the process exits,
+/// or receives one of the signals, Rust's standard output and error are flushed, the originals saved
+/// by the outermost active overrides are put back onto the standard streams (including those of
+/// guards leaked with [`mem::forget`]), and relays are given up to a second to write out what they
+/// have. A signal is then raised again with its default action, so the process still dies of it.
+///
+/// Signals that already have a handler are left to it. If this fails nothing is left installed.
+/// Calling it again does nothing, and returns what the first call returned.
+/// ```rust,no_run
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::{restore_on_exit, Capture};
+///
+/// restore_on_exit()?;
+/// let capture = Capture::stdout()?;
+/// // If we are killed here, the terminal gets its standard output back.
+/// # Ok(())
+/// # }
+/// ```
+pub fn restore_on_exit() -> io::Result<()> {
+    // io::Error can't be cloned, so what is kept of an error is its kind and message.
+    static INSTALLED: OnceLock<Result<(), (io::ErrorKind, String)>> = OnceLock::new();
+    match INSTALLED.get_or_init(|| install().map_err(|e| (e.kind(), e.to_string()))) {
+        Ok(()) => Ok(()),
+        Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+    }
+}
+
+fn install() -> io::Result<()> {
+    let (reader, writer) = os_pipe::pipe()?;
+    thread::Builder::new().name("stdio-override restore".into()).spawn(move || on_signals(reader))?;
+    SIGNAL_PIPE.store(crate::imp::into_raw(writer), Ordering::Release);
+
+    let mut hooked = Vec::new();
+    let res = hook_signals(&mut hooked).and_then(|()| match unsafe { libc::atexit(at_exit) } {
+        0 => Ok(()),
+        _ => Err(io::Error::other("failed to register an atexit hook")),
+    });
+    if res.is_err() {
+        for (signal, previous) in hooked {
+            unsafe { libc::sigaction(signal, &previous, ptr::null_mut()) };
+        }
+        // Closing the pipe stops the restoring thread.
+        let _ = crate::imp::close(SIGNAL_PIPE.swap(-1, Ordering::AcqRel));
+    }
+    res
+}
+
+/// Install the handler for each signal that doesn't have one, adding the signals and their previous
+/// actions to `hooked`.
+fn hook_signals(hooked: &mut Vec<(c_int, libc::sigaction)>) -> io::Result<()> {
+    for &signal in &SIGNALS {
+        unsafe {
+            let mut previous: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, ptr::null(), &mut previous);
+            if previous.sa_sigaction != libc::SIG_DFL {
+                continue;
+            }
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            action.sa_flags = libc::SA_RESTART;
+            if libc::sigaction(signal, &action, ptr::null_mut()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            hooked.push((signal, previous));
+        }
+    }
+    Ok(())
+}
+
+fn restore() {
+    registry::restore_all();
+    relay::wait_for_all(RELAY_TIMEOUT);
+}
+
+/// Rust's standard output has already been flushed by the time `exit` runs the hook, by returning
+/// from `main` or by `process::exit`. Flushing it here could block on a lock held by another thread.
+extern "C" fn at_exit() {
+    restore();
+}
+
+/// Restoring takes locks, so the handler only passes the signal on to a thread that does it.
+extern "C" fn on_signal(signal: c_int) {
+    let byte = signal as u8;
+    unsafe {
+        let errno = errno_location();
+        let saved = errno.map(|errno| *errno);
+        libc::write(SIGNAL_PIPE.load(Ordering::Acquire), (&byte as *const u8).cast(), 1);
+        if let (Some(errno), Some(saved)) = (errno, saved) {
+            *errno = saved;
+        }
+    }
+}
+
+/// Where the calling thread's `errno` is, on the platforms where we know how to find it.
+unsafe fn errno_location() -> Option<*mut c_int> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "emscripten"))]
+    return Some(libc::__errno_location());
+    #[cfg(any(target_vendor = "apple", target_os = "freebsd", target_os = "dragonfly"))]
+    return Some(libc::__error());
+    #[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+    return Some(libc::__errno());
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    return Some(libc::___errno());
+    #[allow(unreachable_code)]
+    None
+}
+
+fn on_signals(mut reader: PipeReader) {
+    let mut signal = [0];
+    while reader.read_exact(&mut signal).is_ok() {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        restore();
+        unsafe {
+            libc::signal(c_int::from(signal[0]), libc::SIG_DFL);
+            libc::raise(c_int::from(signal[0]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+    use crate::{Relay, StdoutOverride};
+
+    /// Run `child` in a forked process whose standard output is a pipe, and return what it wrote
+    /// there and its wait status.
+    fn in_child(child: impl FnOnce()) -> io::Result<(String, c_int)> {
+        let (mut reader, writer) = os_pipe::pipe()?;
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => unsafe {
+                drop(reader);
+                libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO);
+                drop(writer);
+                child();
+                libc::_exit(0);
+            },
+            pid => {
+                drop(writer);
+                let mut output = String::new();
+                reader.read_to_string(&mut output)?;
+                let mut status = 0;
+                assert_eq!(pid, unsafe { libc::waitpid(pid, &mut status, 0) });
+                Ok((output, status))
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_on_sigterm() -> io::Result<()> {
+        let (output, status) = in_child(|| {
+            restore_on_exit().unwrap();
+            mem::forget(StdoutOverride::from_relay(Relay::fan_out(Vec::new()).tee()).unwrap());
+            println!("relayed before the signal");
+            unsafe { libc::raise(libc::SIGTERM) };
+            loop {
+                thread::park();
+            }
+        })?;
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::SIGTERM, libc::WTERMSIG(status));
+        assert_eq!("relayed before the signal\n", output);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_at_exit() -> io::Result<()> {
+        extern "C" fn after_restore() {
+            let message = b"written after the restore\n";
+            unsafe { libc::write(libc::STDOUT_FILENO, message.as_ptr().cast(), message.len()) };
+        }
+        let (output, status) = in_child(|| unsafe {
+            // atexit hooks run in reverse, so this runs after ours.
+            libc::atexit(after_restore);
+            restore_on_exit().unwrap();
+            mem::forget(StdoutOverride::from_file("/dev/null").unwrap());
+            println!("lost");
+            libc::exit(0);
+        })?;
+        assert!(libc::WIFEXITED(status));
+        assert_eq!("written after the restore\n", output);
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Once;
+
+use crate::registry;
+use crate::{imp, lock, relay};
+
+/// What a forked child does with the overrides it inherits.
+///
+/// A child inherits the overridden file descriptors but none of the threads, so output sent to a
+/// relay's pipe is never drained, and a child that writes enough of it hangs. Whatever the policy,
+/// the guards the child inherits stop owning the override: dropping them only closes the child's
+/// copy of the saved original, and new overrides can be made in the child as usual.
+///
+/// Relays can't be recreated in the child, as their sinks belong to the parent's relay threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForkPolicy {
+    /// Put back the originals saved by the outermost overrides.
+    #[default]
+    Restore,
+    /// Point the overridden streams at `/dev/null`.
+    Discard,
+    /// Leave the streams pointing where they did in the parent.
+    Inherit,
+}
+
+/// Bumped in each forked child, so guards made before the fork can tell they are stale.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static POLICY: AtomicU8 = AtomicU8::new(ForkPolicy::Restore as u8);
+
+pub(crate) fn generation() -> usize {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Apply `policy` in every child forked from now on, using `pthread_atfork`.
+///
+/// Until this is first called, forked children are left as they are, and
+/// [`after_fork_in_child`] can be called in them instead. Later calls change the policy.
+///
+/// The handler can't tell whether the fork is about to be followed by an `exec`, so the policy
+/// also applies to children started with [`std::process::Command`] whenever it forks to start
+/// them, which it does for example when `pre_exec` is used or `posix_spawn` can't be. With
+/// [`ForkPolicy::Restore`] or [`ForkPolicy::Discard`] such a child then doesn't inherit the
+/// overridden streams. Give the child its stdio explicitly, or use [`ForkPolicy::Inherit`], if it
+/// should.
+pub fn set_fork_policy(policy: ForkPolicy) -> io::Result<()> {
+    static REGISTER: Once = Once::new();
+    POLICY.store(policy as u8, Ordering::SeqCst);
+    let mut res = Ok(());
+    REGISTER.call_once(|| {
+        if unsafe { libc::pthread_atfork(None, None, Some(in_child)) } != 0 {
+            res = Err(io::Error::other("failed to register a fork handler"));
+        }
+    });
+    res
+}
+
+extern "C" fn in_child() {
+    let policy = match POLICY.load(Ordering::SeqCst) {
+        p if p == ForkPolicy::Discard as u8 => ForkPolicy::Discard,
+        p if p == ForkPolicy::Inherit as u8 => ForkPolicy::Inherit,
+        _ => ForkPolicy::Restore,
+    };
+    after_fork_in_child(policy);
+}
+
+/// Apply `policy` to the overrides inherited by a forked child.
+///
+/// Call this in the child straight after `fork`, before it starts any threads. It only uses
+/// async-signal-safe calls.
+/// ```rust,no_run
+/// use stdio_override::{after_fork_in_child, Capture, ForkPolicy};
+///
+/// let capture = Capture::stdout().unwrap();
+/// if unsafe { libc::fork() } == 0 {
+///     after_fork_in_child(ForkPolicy::Discard);
+///     println!("this goes to /dev/null instead of a pipe nobody reads");
+///     unsafe { libc::_exit(0) };
+/// }
+/// ```
+pub fn after_fork_in_child(policy: ForkPolicy) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        registry::after_fork(|stream, original| {
+            let _ = match policy {
+                ForkPolicy::Restore => imp::restore(stream, original),
+                ForkPolicy::Discard => imp::discard(stream),
+                ForkPolicy::Inherit => Ok(()),
+            };
+        });
+    }
+    for count in [&crate::OVERRIDDEN_STDIN_COUNT, &crate::OVERRIDDEN_STDOUT_COUNT, &crate::OVERRIDDEN_STDERR_COUNT] {
+        count.store(0, Ordering::SeqCst);
+    }
+    relay::forget_threads();
+    lock::after_fork();
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+    use std::mem;
+
+    use super::*;
+    use crate::{Capture, StdoutOverride};
+
+    fn stat(fd: libc::c_int) -> (libc::dev_t, libc::ino_t) {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        assert_eq!(0, unsafe { libc::fstat(fd, &mut stat) });
+        (stat.st_dev, stat.st_ino)
+    }
+
+    #[test]
+    fn test_fork_policies() -> io::Result<()> {
+        let original = stat(libc::STDOUT_FILENO);
+        let dev_null = unsafe { libc::open(CString::new("/dev/null")?.as_ptr(), libc::O_WRONLY) };
+        let dev_null_stat = stat(dev_null);
+        unsafe { libc::close(dev_null) };
+
+        for policy in [ForkPolicy::Restore, ForkPolicy::Discard, ForkPolicy::Inherit] {
+            set_fork_policy(policy)?;
+            let capture = Capture::stdout()?;
+            let captured = stat(libc::STDOUT_FILENO);
+            match unsafe { libc::fork() } {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {
+                    let expected = match policy {
+                        ForkPolicy::Restore => original,
+                        ForkPolicy::Discard => dev_null_stat,
+                        ForkPolicy::Inherit => captured,
+                    };
+                    let mut ok = stat(libc::STDOUT_FILENO) == expected;
+                    // The inherited guards can be dropped, and new ones made.
+                    drop(capture);
+                    ok &= StdoutOverride::from_file("/dev/null").and_then(StdoutOverride::reset).is_ok();
+                    unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+                }
+                child => {
+                    let mut status = 0;
+                    assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "{:?} failed", policy);
+                    capture.finish()?;
+                }
+            }
+        }
+        set_fork_policy(ForkPolicy::Restore)
+    }
+}
@@ -0,0 +1,117 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::fs::File;
+use std::io::{self, Write};
+use std::panic::{self, PanicHookInfo};
+use std::thread;
+
+use crate::capture;
+use crate::registry::{self, Stream};
+
+/// A panic hook that reports panics to the original standard error while it is overridden.
+///
+/// Without this, a panic while standard error is overridden is reported to wherever the override
+/// points, often a pipe or `/dev/null`. With it installed, the panic message and a backtrace are
+/// written to the standard error saved by the outermost [`StderrOverride`](crate::StderrOverride),
+/// optionally followed by everything the live [`Capture`](crate::Capture)s have captured so far.
+/// As with the default hook, the backtrace is only captured if `RUST_BACKTRACE` or
+/// `RUST_LIB_BACKTRACE` asks for it.
+///
+/// The previously installed hook is called afterwards, so it still reports the panic wherever it
+/// would have.
+/// ```rust,no_run
+/// use stdio_override::{Capture, PanicHook};
+///
+/// PanicHook::new().include_captured(true).install();
+/// let capture = Capture::both().unwrap();
+/// println!("shown with the panic");
+/// panic!("this reaches the terminal");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PanicHook {
+    include_captured: bool,
+}
+impl PanicHook {
+    /// Create a hook that only reports the panic message and backtrace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Whether to also report the output captured so far. Defaults to `false`.
+    pub fn include_captured(mut self, include_captured: bool) -> Self {
+        self.include_captured = include_captured;
+        self
+    }
+    /// Install the hook, chaining to the currently installed one.
+    pub fn install(self) {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(original) = registry::outermost(Stream::Stderr) {
+                let _ = self.report(&original, info);
+            }
+            previous(info);
+        }));
+    }
+    fn report(&self, mut original: &File, info: &PanicHookInfo) -> io::Result<()> {
+        let thread = thread::current();
+        writeln!(original, "thread '{}' {}", thread.name().unwrap_or("<unnamed>"), info)?;
+        let backtrace = Backtrace::capture();
+        match backtrace.status() {
+            BacktraceStatus::Captured => writeln!(original, "stack backtrace:\n{}", backtrace)?,
+            _ => writeln!(original, "note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace")?,
+        }
+        if self.include_captured {
+            let captured = capture::captured_so_far();
+            writeln!(original, "---- captured stdout ----\n{}", captured.stdout)?;
+            writeln!(original, "---- captured stderr ----\n{}", captured.stderr)?;
+        }
+        original.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{Capture, StderrOverride};
+
+    #[test]
+    fn test_panic_reaches_original_stderr() -> io::Result<()> {
+        // Whether backtraces are enabled is decided once, so the child decides the same.
+        let backtraces = Backtrace::capture().status() == BacktraceStatus::Captured;
+        let (mut reader, writer) = os_pipe::pipe()?;
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => unsafe {
+                drop(reader);
+                libc::dup2(writer.as_raw_fd(), libc::STDERR_FILENO);
+                drop(writer);
+                PanicHook::new().include_captured(true).install();
+                let _stderr = StderrOverride::from_file("/dev/null").unwrap();
+                let capture = Capture::stdout().unwrap();
+                // Not println!, which the test harness would capture.
+                writeln!(io::stdout(), "before the panic").unwrap();
+                io::stdout().flush().unwrap();
+                // If it never arrives, the report is missing it.
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while capture.contents().stdout.is_empty() && Instant::now() < deadline {
+                    thread::yield_now();
+                }
+                let _ = panic::catch_unwind(|| panic!("the panic message"));
+                libc::_exit(0);
+            },
+            child => {
+                drop(writer);
+                let mut report = String::new();
+                reader.read_to_string(&mut report)?;
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(report.contains("the panic message"), "{}", report);
+                assert_eq!(backtraces, report.contains("stack backtrace:"), "{}", report);
+                assert!(report.contains("---- captured stdout ----\nbefore the panic\n"), "{}", report);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,584 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use os_pipe::PipeWriter;
+#[cfg(feature = "regex")]
+use regex::{bytes, Regex};
+
+use crate::capture::{Buffer, BufferSink};
+use crate::{Relay, StdinOverride, StdoutOverride};
+
+/// How long each expectation waits by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often an expectation checks for read input to echo while nothing is printed.
+const ECHO_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Waits for the code under test to finish until the deadline, and ends the override. A panic in
+/// the code is returned rather than resumed, so that dropping an interaction doesn't panic.
+type Finish<T> = Box<dyn FnOnce(Instant) -> io::Result<thread::Result<T>>>;
+
+/// Drives interactive code, like a REPL, by writing to its standard input and waiting for what it
+/// prints to standard output.
+///
+/// [`Interaction::thread`] runs the code on a thread while standard input and output are
+/// overridden with pipes, and [`Interaction::fork`] runs it in a forked child instead, leaving this
+/// process's streams alone. Every expectation waits for at most the [`timeout`](Self::timeout), and
+/// consumes the output up to the end of its match, so the next one only looks at what comes after.
+///
+/// With [`echo`](Self::echo) on, input the code reads is echoed into its output, as a terminal
+/// would, so the [`Transcript`] reads like the session did.
+///
+/// Rust's standard output is line buffered, so prompts that don't end in a newline must be flushed
+/// by the code under test, as they would need to be at a terminal.
+///
+/// Dropping an interaction closes the code's standard input and waits for the code to finish, for
+/// at most the timeout, before ending the overrides. If it doesn't finish in time, the overrides
+/// are ended anyway, and what it prints afterwards goes wherever standard output went before.
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use std::io::{self, BufRead, Write};
+/// use stdio_override::Interaction;
+///
+/// let mut repl = Interaction::thread(|| {
+///     let mut line = String::new();
+///     loop {
+///         print!("> ");
+///         io::stdout().flush().unwrap();
+///         line.clear();
+///         if io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+///             return "bye";
+///         }
+///         println!("{}", line.trim().to_uppercase());
+///     }
+/// })?;
+/// repl.expect("> ")?;
+/// repl.send_line("hello")?;
+/// repl.expect("HELLO\n> ")?;
+/// assert_eq!("bye", repl.expect_eof()?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Interaction<T> {
+    stdin: Option<Input>,
+    stdout: Arc<Buffer>,
+    /// Where in the standard output the next expectation starts.
+    position: u64,
+    timeout: Duration,
+    echo: bool,
+    finish: Option<Finish<T>>,
+}
+impl<T: Send + 'static> Interaction<T> {
+    /// Run `f` on a new thread, with standard input and output overridden with pipes.
+    ///
+    /// The overrides affect the whole process, so other threads' output is seen too.
+    #[track_caller]
+    pub fn thread<F: FnOnce() -> T + Send + 'static>(f: F) -> io::Result<Self> {
+        let stdout = Arc::new(Buffer::new(None));
+        let stdout_guard = StdoutOverride::from_relay(Relay::new(BufferSink(Arc::clone(&stdout))))?;
+        let (reader, writer) = os_pipe::pipe()?;
+        stdout.echo_from(reader.try_clone()?);
+        let stdin_guard = StdinOverride::from_io(reader)?;
+
+        let (sender, result) = mpsc::channel();
+        thread::Builder::new().name("stdio-override interaction".into()).spawn(move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        })?;
+        let finish = move |deadline: Instant| {
+            let result = match result.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the code to return"))
+                }
+                Err(RecvTimeoutError::Disconnected) => unreachable!("the thread always sends its result"),
+            };
+            io::stdout().flush()?;
+            stdin_guard.reset()?;
+            stdout_guard.reset()?;
+            Ok(result)
+        };
+        Ok(Self::new(writer, stdout, Box::new(finish)))
+    }
+}
+impl<T> Interaction<T> {
+    fn new(stdin: PipeWriter, stdout: Arc<Buffer>, finish: Finish<T>) -> Self {
+        Self { stdin: Some(Input::new(stdin)), stdout, position: 0, timeout: DEFAULT_TIMEOUT, echo: false, finish: Some(finish) }
+    }
+    /// How long each expectation waits before failing with [`io::ErrorKind::TimedOut`]. Ten
+    /// seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Whether input sent from now on is echoed into the output once the code reads it. Off by
+    /// default.
+    ///
+    /// Input is echoed where it was read from the pipe: std's standard input reads ahead, so lines
+    /// sent together are echoed together. Expectations see the echoed input too.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+    /// Write `input` to the code's standard input.
+    pub fn send(&mut self, input: &str) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(Input(stdin)) if self.echo => self.stdout.send_echoed(stdin, input.as_bytes()),
+            Some(Input(stdin)) => stdin.write_all(input.as_bytes()),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "standard input is closed")),
+        }
+    }
+    /// Write `line` and a newline to the code's standard input.
+    pub fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.send(&format!("{}\n", line))
+    }
+    /// Close the code's standard input, so it reads the end of the file.
+    pub fn send_eof(&mut self) {
+        self.stdin = None;
+    }
+    /// Wait for the code to print `text`, and return the output up to the end of it.
+    pub fn expect(&mut self, text: &str) -> io::Result<String> {
+        let text = text.as_bytes();
+        let find = |output: &[u8]| match text.len() {
+            0 => Some(0),
+            len => output.windows(len).position(|window| window == text).map(|start| start + len),
+        };
+        self.expect_bytes(&find, &format!("{:?}", String::from_utf8_lossy(text)))
+    }
+    /// Wait for the code to print something matching `regex`, and return the output up to the end
+    /// of the match.
+    ///
+    /// Needs the `regex` feature, which `redact` and `normalize` also enable.
+    #[cfg(feature = "regex")]
+    pub fn expect_regex(&mut self, regex: &Regex) -> io::Result<String> {
+        let bytes = bytes::Regex::new(regex.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.expect_bytes(&|output| bytes.find(output).map(|found| found.end()), &format!("/{}/", regex))
+    }
+    fn expect_bytes(&mut self, find: &dyn Fn(&[u8]) -> Option<usize>, expected: &str) -> io::Result<String> {
+        let deadline = Instant::now() + self.timeout;
+        let found = loop {
+            if !self.echo {
+                break self.stdout.expect(&mut self.position, find, deadline);
+            }
+            // Input is echoed as output arrives, so look for input read without any since.
+            self.stdout.echo_read_input();
+            match self.stdout.expect(&mut self.position, find, deadline.min(Instant::now() + ECHO_INTERVAL)) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && Instant::now() < deadline => continue,
+                found => break found,
+            }
+        };
+        match found {
+            Ok(Some(output)) => Ok(output),
+            Ok(None) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("standard output ended while expecting {}, after {:?}", expected, self.stdout.pending(self.position)),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {:?} expecting {}, got {:?}", self.timeout, expected, self.stdout.pending(self.position)),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+    /// Close the code's standard input, wait for it to finish, and end the overrides.
+    ///
+    /// Returns what the code returned. If it panicked, the panic is resumed here.
+    pub fn expect_eof(self) -> io::Result<T> {
+        self.finish().map(|(value, _)| value)
+    }
+    /// Like [`expect_eof`](Self::expect_eof), but also return the whole transcript.
+    pub fn finish(mut self) -> io::Result<(T, Transcript)> {
+        self.send_eof();
+        let finish = self.finish.take().expect("only taken here");
+        let value = finish(Instant::now() + self.timeout)?.unwrap_or_else(|payload| panic::resume_unwind(payload));
+        Ok((value, self.transcript()))
+    }
+    /// Everything printed so far, including what was already expected, and any echoed input.
+    pub fn transcript(&self) -> Transcript {
+        let (bytes, echoes) = self.stdout.with_echoes();
+        let mut transcript = Transcript::default();
+        let mut printed = 0;
+        for echo in echoes {
+            transcript.text.push_str(&String::from_utf8_lossy(&bytes[printed..echo.start]));
+            let start = transcript.text.len();
+            transcript.text.push_str(&String::from_utf8_lossy(&bytes[echo.clone()]));
+            transcript.echoed.push(start..transcript.text.len());
+            printed = echo.end;
+        }
+        transcript.text.push_str(&String::from_utf8_lossy(&bytes[printed..]));
+        transcript
+    }
+    /// The output printed since the last expectation, without consuming it.
+    pub fn pending(&self) -> String {
+        self.stdout.pending(self.position)
+    }
+}
+/// Everything an [`Interaction`]'s code printed, with the input it echoed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// The output and echoed input, in order. Invalid UTF-8 is replaced with `U+FFFD`.
+    pub text: String,
+    /// Where the echoed input is in the text.
+    pub echoed: Vec<Range<usize>>,
+}
+impl Transcript {
+    /// The text without the echoed input.
+    pub fn without_echo(&self) -> String {
+        let mut text = String::new();
+        let mut printed = 0;
+        for echo in &self.echoed {
+            text.push_str(&self.text[printed..echo.start]);
+            printed = echo.end;
+        }
+        text.push_str(&self.text[printed..]);
+        text
+    }
+}
+
+impl<T> fmt::Debug for Interaction<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interaction")
+            .field("position", &self.position)
+            .field("timeout", &self.timeout)
+            .field("echo", &self.echo)
+            .finish_non_exhaustive()
+    }
+}
+impl<T> Drop for Interaction<T> {
+    fn drop(&mut self) {
+        // Closing standard input first lets code waiting on it finish on its own.
+        self.stdin = None;
+        if let Some(finish) = self.finish.take() {
+            let _ = finish(Instant::now() + self.timeout);
+        }
+    }
+}
+
+/// The write end of the code's standard input.
+///
+/// On Linux, children forked by [`Interaction::fork`] close those of every other live interaction,
+/// so that the code still reads the end of the file once the interaction closes its own.
+struct Input(PipeWriter);
+impl Input {
+    fn new(writer: PipeWriter) -> Self {
+        #[cfg(target_os = "linux")]
+        child::stdin_writers().push(writer.as_raw_fd());
+        Self(writer)
+    }
+}
+impl Drop for Input {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        child::stdin_writers().retain(|&writer| writer != self.0.as_raw_fd());
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod child {
+    use std::io::{self, Write};
+    use std::os::unix::io::RawFd;
+    use std::os::unix::process::ExitStatusExt;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::process::ExitStatus;
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::Interaction;
+    use crate::capture::{Buffer, BufferSink};
+    use crate::{after_fork_in_child, ForkPolicy, StdinOverride, StdoutOverride};
+
+    /// The exit code of a child whose code panicked, matching that of a Rust program that panics.
+    const PANIC_CODE: i32 = 101;
+
+    /// The write ends of the standard inputs of live interactions.
+    static STDIN_WRITERS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+    pub(super) fn stdin_writers() -> MutexGuard<'static, Vec<RawFd>> {
+        STDIN_WRITERS.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A forked child, killed and reaped if it is dropped before it exits.
+    struct Child(libc::pid_t);
+    impl Child {
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            let mut status = 0;
+            match unsafe { libc::waitpid(self.0, &mut status, libc::WNOHANG) } {
+                -1 => Err(io::Error::last_os_error()),
+                0 => Ok(None),
+                _ => {
+                    self.0 = 0;
+                    Ok(Some(ExitStatus::from_raw(status)))
+                }
+            }
+        }
+    }
+    impl Drop for Child {
+        fn drop(&mut self) {
+            if self.0 != 0 {
+                unsafe {
+                    libc::kill(self.0, libc::SIGKILL);
+                    libc::waitpid(self.0, &mut 0, 0);
+                }
+            }
+        }
+    }
+
+    impl Interaction<ExitStatus> {
+        /// Run `f` in a forked child whose standard input and output are pipes, leaving this
+        /// process's streams alone.
+        ///
+        /// The child exits with code 101 if `f` panics, and [`expect_eof`](Self::expect_eof)
+        /// returns its exit status. Only the thread calling this exists in the child, and its
+        /// standard error is this process's.
+        pub fn fork<F: FnOnce()>(f: F) -> io::Result<Self> {
+            let (stdin_reader, stdin_writer) = os_pipe::pipe()?;
+            let (mut stdout_reader, stdout_writer) = os_pipe::pipe()?;
+            let pid = {
+                // As in `isolated::run`, nobody else holds the locks in the child, and it doesn't
+                // write out what we printed. Standard input's lock is held too, because the child
+                // takes it to override standard input.
+                let stdin = io::stdin().lock();
+                let (mut stdout, mut stderr) = (io::stdout().lock(), io::stderr().lock());
+                stdout.flush()?;
+                stderr.flush()?;
+                // Held across the fork, so the child has a consistent copy.
+                let writers = stdin_writers();
+                match unsafe { libc::fork() } {
+                    -1 => return Err(io::Error::last_os_error()),
+                    0 => {
+                        for &writer in writers.iter() {
+                            unsafe { libc::close(writer) };
+                        }
+                        drop((stdin, stdout, stderr, writers, stdin_writer, stdout_reader));
+                        after_fork_in_child(ForkPolicy::Inherit);
+                        // The overrides last until the child exits.
+                        let overrides = StdinOverride::from_io(stdin_reader)
+                            .and_then(|stdin| Ok((stdin, StdoutOverride::from_io(stdout_writer)?)));
+                        let code = match overrides {
+                            Ok(_overrides) if panic::catch_unwind(AssertUnwindSafe(f)).is_ok() => 0,
+                            _ => PANIC_CODE,
+                        };
+                        let _ = io::stdout().flush();
+                        unsafe { libc::_exit(code) }
+                    }
+                    pid => Child(pid),
+                }
+            };
+            drop(stdout_writer);
+
+            let stdout = Arc::new(Buffer::new(None));
+            stdout.echo_from(stdin_reader);
+            let mut sink = BufferSink(Arc::clone(&stdout));
+            let reader = thread::Builder::new()
+                .name("stdio-override interaction".into())
+                .spawn(move || io::copy(&mut stdout_reader, &mut sink).map(drop))?;
+            let mut child = pid;
+            let finish = move |deadline: Instant| loop {
+                if let Some(status) = child.try_wait()? {
+                    return match reader.join() {
+                        Ok(copied) => copied.map(|()| Ok(status)),
+                        Err(payload) => Ok(Err(payload)),
+                    };
+                }
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the child to exit"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            Ok(Self::new(stdin_writer, stdout, Box::new(finish)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, Read};
+
+    use crate::Capture;
+
+    use super::*;
+
+    fn repl() -> &'static str {
+        let mut line = String::new();
+        loop {
+            print!("> ");
+            io::stdout().flush().unwrap();
+            line.clear();
+            if io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+                return "bye";
+            }
+            match line.trim() {
+                "panic" => panic!("asked to"),
+                "quit" => return "quit",
+                line => println!("{} has {} letters", line, line.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_thread() -> io::Result<()> {
+        let mut interaction = Interaction::thread(repl)?;
+        assert_eq!("> ", interaction.expect("> ")?);
+        interaction.send_line("hello")?;
+        assert_eq!("hello has 5 letters\n", interaction.expect("letters\n")?);
+        interaction.expect("> ")?;
+        #[cfg(feature = "regex")]
+        {
+            interaction.send_line("hi")?;
+            assert_eq!("hi has 2 letters\n", interaction.expect_regex(&Regex::new(r"has \d+ letters\n").unwrap())?);
+            interaction.expect("> ")?;
+        }
+        let mut interaction = interaction.timeout(Duration::from_millis(50));
+        let timed_out = interaction.expect("never printed").unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, timed_out.kind());
+        assert!(timed_out.to_string().ends_with("expecting \"never printed\", got \"\""), "{}", timed_out);
+        assert_eq!("bye", interaction.expect_eof()?);
+
+        let interaction = Interaction::thread(repl)?.timeout(Duration::from_secs(5));
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut interaction = interaction;
+            interaction.send_line("panic").unwrap();
+            interaction.expect_eof()
+        }));
+        assert!(panicked.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_echo() -> io::Result<()> {
+        let mut interaction = Interaction::thread(|| {
+            let mut line = String::new();
+            // Reads without printing anything, which is echoed once read.
+            io::stdin().read_line(&mut line).unwrap();
+            repl()
+        })?
+        .echo(true);
+        interaction.send_line("silent")?;
+        interaction.expect("silent\n> ")?;
+        interaction.send_line("hello")?;
+        interaction.expect("hello\nhello has 5 letters\n> ")?;
+        let (value, transcript) = interaction.finish()?;
+
+        assert_eq!("bye", value);
+        assert_eq!("silent\n> hello\nhello has 5 letters\n> ", transcript.text);
+        assert_eq!(vec![0..7, 9..15], transcript.echoed);
+        assert_eq!("> hello has 5 letters\n> ", transcript.without_echo());
+        Ok(())
+    }
+
+    #[test]
+    fn test_echo_while_printing() -> io::Result<()> {
+        const LEN: usize = 256 * 1024;
+        let mut interaction = Interaction::thread(|| {
+            // More than fits in either pipe, printed before any input is read.
+            writeln!(io::stdout(), "{}", "o".repeat(LEN)).unwrap();
+            io::stdin().read_to_end(&mut Vec::new()).unwrap()
+        })?
+        .echo(true);
+        interaction.send(&"i".repeat(LEN))?;
+        let (read, transcript) = interaction.finish()?;
+
+        assert_eq!(LEN, read);
+        assert_eq!(LEN, transcript.echoed.iter().map(|echo| echo.len()).sum::<usize>());
+        assert_eq!(LEN + 1, transcript.without_echo().len());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fork() -> io::Result<()> {
+        let mut interaction = Interaction::fork(|| {
+            repl();
+        })?;
+        interaction.expect("> ")?;
+        interaction.send_line("forked")?;
+        interaction.expect("forked has 6 letters\n> ")?;
+        interaction.send_line("quit")?;
+        let eof = interaction.expect("anything").unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, eof.kind());
+        assert!(interaction.expect_eof()?.success());
+
+        let mut interaction = Interaction::fork(|| {
+            repl();
+        })?;
+        interaction.send_line("panic")?;
+        assert_eq!(Some(101), interaction.expect_eof()?.code());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fork_closes_other_inputs() -> io::Result<()> {
+        let first = Interaction::fork(|| {
+            repl();
+        })?
+        .timeout(Duration::from_secs(5));
+        // Forked while the first is live, so it inherits the first's standard input.
+        let mut second = Interaction::fork(|| {
+            repl();
+        })?;
+        assert!(first.expect_eof()?.success());
+        second.expect("> ")?;
+        assert!(second.expect_eof()?.success());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fork_while_stdin_is_locked() -> io::Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc;
+
+        // Another thread holds standard input's lock nearly all the time, and when we fork.
+        let stop = Arc::new(AtomicBool::new(false));
+        let (locked, is_locked) = mpsc::channel();
+        let locker = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let stdin = io::stdin().lock();
+                    let _ = locked.send(());
+                    thread::sleep(Duration::from_millis(20));
+                    drop(stdin);
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        is_locked.recv().unwrap();
+        let res = Interaction::fork(|| {
+            repl();
+        })
+        .and_then(|interaction| {
+            let mut interaction = interaction.timeout(Duration::from_secs(5));
+            interaction.expect("> ")?;
+            interaction.expect_eof()
+        });
+        stop.store(true, Ordering::SeqCst);
+        locker.join().unwrap();
+        assert!(res?.success());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_waits_for_code() -> io::Result<()> {
+        let capture = Capture::stdout()?;
+        let interaction = Interaction::thread(|| {
+            io::stdin().read_to_end(&mut Vec::new()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            // Not println!, which the test harness would capture.
+            writeln!(io::stdout(), "after the end of the input").unwrap();
+        })?;
+        drop(interaction);
+        // Gives code that outlived the overrides time to print.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!("", capture.finish()?.stdout);
+        Ok(())
+    }
+}
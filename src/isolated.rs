@@ -0,0 +1,167 @@
+//! Running closures in a forked child process, with the standard streams captured there.
+//!
+//! Overrides change the standard streams of the whole process, so tests that capture output can't
+//! run in parallel. [`run`] forks instead, overrides the streams only in the child, and sends the
+//! output and the closure's return value back to the parent, whose own streams never change.
+//!
+//! This is only available on Linux, with the `isolated` feature.
+//!
+//! Output printed with `print!` still goes to the test harness's capture when tests aren't run with
+//! `--nocapture`. Only the thread calling [`run`] exists in the child, so the closure must not wait
+//! on anything other threads would do, and any file descriptors the parent has open while forking
+//! stay open in the child until it exits.
+//! ```rust
+//! # fn main() -> std::io::Result<()> {
+//! use stdio_override::isolated;
+//!
+//! let isolated = isolated::run(|| {
+//!     println!("in the child");
+//!     6 * 7
+//! })?;
+//! assert_eq!(Some(42), isolated.value);
+//! assert_eq!("in the child\n", isolated.stdout);
+//! assert!(isolated.status.success());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::ExitStatus;
+use std::thread;
+
+use os_pipe::{PipeReader, PipeWriter};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{after_fork_in_child, ForkPolicy};
+
+/// The exit code of a child whose closure panicked, matching that of a Rust program that panics.
+const PANIC_CODE: i32 = 101;
+
+/// The outcome of a closure run by [`run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Isolated<T> {
+    /// What the closure returned, or `None` if it panicked or the child died.
+    pub value: Option<T>,
+    /// How the child exited. It exits with code 101 if the closure panicked.
+    pub status: ExitStatus,
+    /// Everything the child wrote to standard output. Invalid UTF-8 is replaced with `U+FFFD`.
+    pub stdout: String,
+    /// Everything the child wrote to standard error, including any panic message.
+    pub stderr: String,
+}
+
+/// Run `f` in a forked child process whose standard output and error are captured, and wait for it.
+///
+/// The return value is serialized with `serde_json` to be sent back to the parent.
+pub fn run<T, F>(f: F) -> io::Result<Isolated<T>>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> T,
+{
+    let (stdout_reader, stdout_writer) = os_pipe::pipe()?;
+    let (stderr_reader, stderr_writer) = os_pipe::pipe()?;
+    let (mut value_reader, value_writer) = os_pipe::pipe()?;
+
+    let pid = {
+        // Holding the locks while forking means no other thread holds them in the child, and
+        // flushing first means the child doesn't write out what the parent printed.
+        let (mut stdout, mut stderr) = (io::stdout().lock(), io::stderr().lock());
+        stdout.flush()?;
+        stderr.flush()?;
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                drop((stdout, stderr, stdout_reader, stderr_reader, value_reader));
+                in_child(f, stdout_writer, stderr_writer, value_writer)
+            }
+            pid => pid,
+        }
+    };
+    drop((stdout_writer, stderr_writer, value_writer));
+
+    let stdout = thread::Builder::new().name("stdio-override isolated stdout".into()).spawn(move || read_lossy(stdout_reader))?;
+    let stderr = thread::Builder::new().name("stdio-override isolated stderr".into()).spawn(move || read_lossy(stderr_reader))?;
+    let mut value = Vec::new();
+    let read_value = value_reader.read_to_end(&mut value);
+    let stdout = stdout.join().unwrap_or_else(|_| Err(io::Error::other("reading the child's output panicked")));
+    let stderr = stderr.join().unwrap_or_else(|_| Err(io::Error::other("reading the child's output panicked")));
+    let status = wait(pid)?;
+    read_value?;
+
+    let value = if value.is_empty() { None } else { Some(serde_json::from_slice(&value).map_err(io::Error::other)?) };
+    Ok(Isolated { value, status, stdout: stdout?, stderr: stderr? })
+}
+
+fn in_child<T: Serialize, F: FnOnce() -> T>(f: F, stdout: PipeWriter, stderr: PipeWriter, mut value: PipeWriter) -> ! {
+    after_fork_in_child(ForkPolicy::Inherit);
+    unsafe {
+        libc::dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    drop((stdout, stderr));
+
+    let code = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => match serde_json::to_writer(&mut value, &result) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("failed to send the return value to the parent: {}", e);
+                1
+            }
+        },
+        Err(_) => PANIC_CODE,
+    };
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    unsafe { libc::_exit(code) }
+}
+
+fn read_lossy(mut reader: PipeReader) -> io::Result<String> {
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+fn wait(pid: libc::pid_t) -> io::Result<ExitStatus> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            return Ok(ExitStatus::from_raw(status));
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run() -> io::Result<()> {
+        let isolated = run(|| {
+            print!("to stdout");
+            eprintln!("to stderr");
+            String::from("returned")
+        })?;
+        assert_eq!(Some("returned".to_owned()), isolated.value);
+        assert_eq!("to stdout", isolated.stdout);
+        assert_eq!("to stderr\n", isolated.stderr);
+        assert!(isolated.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_panic() -> io::Result<()> {
+        let isolated = run(|| -> u32 { panic!("in the child") })?;
+        assert_eq!(None, isolated.value);
+        assert_eq!(Some(PANIC_CODE), isolated.status.code());
+        assert!(isolated.stderr.contains("in the child"), "{}", isolated.stderr);
+        Ok(())
+    }
+}
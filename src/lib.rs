@@ -10,10 +10,16 @@
 //! You can create multiple stdio overrides, but if you attempt to drop them out of order then they
 //! will panic.
 //!
-//! You can use the [`os_pipe`](https://docs.rs/os_pipe) crate to capture the standard streams in
-//! memory.
+//! The `pipe` constructors, like [`StdoutOverride::pipe`], override a stream with a new pipe and
+//! return its other end, which can be used to capture the standard streams in memory.
+//!
+//! Standard output and error can also be sent through a [`Relay`], which drains them on a background
+//! thread and passes them through a pipeline of [`transform`]s before writing them to any sink.
+//! [`Capture`] uses relays to capture the streams in memory.
 //!
 //! **Notice:** When trying to use this in tests you **must** run with `cargo test -- --test-threads=1 --nocapture` otherwise it will redirect stdout/stderr again.
+//! Calling [`enable_override_lock`] makes overrides on different threads wait for each other instead,
+//! so only `--nocapture` is needed.
 //!
 //! This library is made to be intuitive and easy to use.
 //!
@@ -81,16 +87,101 @@
 
 use std::fs::File;
 use std::io::{self, IoSlice, IoSliceMut, Read, Write};
-use std::mem::ManuallyDrop;
+use std::mem::{self, ManuallyDrop};
 use std::path::Path;
+use std::process;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use registry::Stream;
+
 #[cfg(not(any(unix, windows)))]
 compile_error!("stdio-override only supports Unix and Windows");
 
+mod ansi;
+mod capture;
+#[cfg(feature = "cast")]
+pub mod cast;
+mod crash;
+#[cfg(unix)]
+mod exit;
+#[cfg(unix)]
+mod fork;
+mod hook;
 #[cfg_attr(unix, path = "unix.rs")]
 #[cfg_attr(windows, path = "windows.rs")]
 mod imp;
+mod interaction;
+#[cfg(all(feature = "isolated", target_os = "linux"))]
+pub mod isolated;
+mod lock;
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod macro_support;
+#[cfg(feature = "normalize")]
+mod normalize;
+pub mod print;
+mod readahead;
+mod registry;
+mod relay;
+mod snapshot;
+mod spin;
+pub mod transform;
+
+pub use ansi::AnsiMode;
+pub use capture::{quiet_unless_failure, Capture, CaptureOptions, Captured, CapturedLines, Overflow};
+pub use crash::{CrashRing, RingBuffer};
+#[cfg(unix)]
+pub use exit::restore_on_exit;
+#[cfg(unix)]
+pub use fork::{after_fork_in_child, set_fork_policy, ForkPolicy};
+pub use hook::PanicHook;
+pub use interaction::{Interaction, Transcript};
+pub use lock::enable_override_lock;
+#[cfg(feature = "normalize")]
+pub use normalize::Normalizer;
+pub use os_pipe::{PipeReader, PipeWriter};
+pub use print::rust_print_is_captured;
+pub use readahead::enable_read_ahead_handoff;
+#[cfg(feature = "regex")]
+pub use regex::Regex;
+pub use relay::{Backpressure, ErrorPolicy, Relay, RelayStats, Sink};
+pub use snapshot::Snapshots;
+/// Run a test with its standard output and error captured. Needs the `macros` feature.
+///
+/// This replaces `#[test]`. Before the body runs, a [`Capture`] of both streams is started. After
+/// the body, Rust's and C's standard output buffers are flushed and the capture is finished. If the
+/// body panics, what it printed is written to the original streams instead.
+///
+/// Like any other overrides, captured tests need `--test-threads=1`, unless the
+/// [override lock](enable_override_lock) is enabled before they start.
+///
+/// The test can take a `&Capture` to look at what has been captured so far, and the `stdout` and
+/// `stderr` arguments assert on what was captured once the body has run.
+///
+/// Without `--nocapture`, the test harness intercepts `print!` and friends before they reach the
+/// capture. The `stdout` and `stderr` arguments are then not checked, and a note saying so is
+/// written to the standard error. Output written with [`io::stdout`], the macros in
+/// [`print`](mod@crate::print) or C's stdio is captured either way:
+/// ```rust
+/// use std::io::{self, Write};
+/// use stdio_override::Capture;
+///
+/// #[stdio_override::capture(stdout = "hello\n", stderr = "")]
+/// fn prints_hello() {
+///     println!("hello");
+/// }
+///
+/// #[stdio_override::capture]
+/// fn sees_output(out: &Capture) -> io::Result<()> {
+///     write!(io::stdout(), "so far")?;
+///     io::stdout().flush()?;
+///     while out.contents().stdout.is_empty() {}
+///     assert_eq!("so far", out.contents().stdout);
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "macros")]
+pub use stdio_override_macros::capture;
 
 static OVERRIDDEN_STDIN_COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -98,60 +189,133 @@ static OVERRIDDEN_STDIN_COUNT: AtomicUsize = AtomicUsize::new(0);
 ///
 /// Reading from this reads the original standard input. When it is dropped the standard input
 /// will be reset.
+///
+/// [`io::stdin`] reads ahead into a buffer, so bytes it read from one stream can be read after
+/// standard input is switched to another. [`enable_read_ahead_handoff`] keeps them from crossing
+/// the override in either direction.
 #[derive(Debug)]
 pub struct StdinOverride {
     original: ManuallyDrop<File>,
     index: usize,
+    /// What `io::stdin` had read ahead from the original standard input, if it was handed off.
+    read_ahead: Option<Vec<u8>>,
+    held: Option<lock::Held>,
+    #[cfg(unix)]
+    generation: usize,
 }
 impl StdinOverride {
+    #[track_caller]
     fn from_raw_inner(raw: imp::Raw, owned: bool) -> io::Result<Self> {
+        let held = lock::acquire("StdinOverride")?;
+        let mut stdin = readahead::is_enabled().then(|| io::stdin().lock());
+        let read_ahead = stdin.as_mut().map(readahead::take).transpose()?;
+        let original = match imp::override_stdin(raw, owned) {
+            Ok(original) => original,
+            Err(e) => {
+                if let (Some(stdin), Some(read_ahead)) = (&mut stdin, &read_ahead) {
+                    let _ = readahead::inject(stdin, read_ahead);
+                }
+                return Err(e);
+            }
+        };
+        let index = OVERRIDDEN_STDIN_COUNT.fetch_add(1, Ordering::SeqCst);
+        registry::register(Stream::Stdin, index, imp::as_raw(&original));
         Ok(Self {
-            original: ManuallyDrop::new(imp::override_stdin(raw, owned)?),
-            index: OVERRIDDEN_STDIN_COUNT.fetch_add(1, Ordering::SeqCst),
+            original: ManuallyDrop::new(original),
+            index,
+            read_ahead,
+            held,
+            #[cfg(unix)]
+            generation: fork::generation(),
         })
     }
     /// Read standard input from the raw file descriptor or handle. It must be readable.
     ///
     /// The stream is not owned, so it is your job to close it later. Closing it while this exists
     /// will not close the standard error.
+    #[track_caller]
     pub fn from_raw(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, false)
     }
     /// Read standard input from the owned raw file descriptor or handle. It must be readable.
     ///
     /// The stream is owned, and so you must not use it after passing it to this function.
+    #[track_caller]
     pub fn from_raw_owned(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, true)
     }
     /// Read standard input from the IO device. The device must be readable.
     ///
     /// Dropping the IO device after calling this function will not close the standard input.
+    #[track_caller]
     pub fn from_io_ref<T: imp::AsRaw>(io: &T) -> io::Result<Self> {
         Self::from_raw(imp::as_raw(io))
     }
     /// Read standard input from the IO device. The device must be readable.
+    #[track_caller]
     pub fn from_io<T: imp::IntoRaw>(io: T) -> io::Result<Self> {
         Self::from_raw_owned(imp::into_raw(io))
     }
     /// Read standard input from the file at that file path.
     ///
     /// The file must exist and be readable.
+    #[track_caller]
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         Self::from_io(File::open(path)?)
     }
+    /// Read standard input from a new pipe, and return the pipe's write end.
+    ///
+    /// The guard owns the read end, so writes fail once the guard is reset instead of going unread.
+    #[track_caller]
+    pub fn pipe() -> io::Result<(Self, PipeWriter)> {
+        let (reader, writer) = os_pipe::pipe()?;
+        Ok((Self::from_io(reader)?, writer))
+    }
+    /// The original standard input, for a child process that should read from it instead of
+    /// the override. Children spawned while the override is active inherit the override otherwise,
+    /// unless a fork policy set with `set_fork_policy` applies to them.
+    pub fn original_for_child(&self) -> io::Result<process::Stdio> {
+        Ok(self.original.try_clone()?.into())
+    }
     /// Reset the standard input to its state before this type was constructed.
     ///
     /// This can be called to manually handle errors produced by the destructor.
-    pub fn reset(self) -> io::Result<()> {
-        self.reset_inner()?;
-        std::mem::forget(self);
-        Ok(())
+    pub fn reset(mut self) -> io::Result<()> {
+        let res = self.reset_inner();
+        mem::forget(self);
+        res
     }
-    fn reset_inner(&self) -> io::Result<()> {
-        if OVERRIDDEN_STDIN_COUNT.swap(self.index, Ordering::SeqCst) <= self.index {
+    fn reset_inner(&mut self) -> io::Result<()> {
+        // Released once the stream is reset.
+        let _held = self.held.take();
+        #[cfg(unix)]
+        if self.generation != fork::generation() {
+            // The override was made before a fork, so it belongs to the parent.
+            return imp::close(imp::as_raw(&*self.original));
+        }
+        if OVERRIDDEN_STDIN_COUNT.load(Ordering::SeqCst) <= self.index {
             panic!("Stdin override reset out of order!");
         }
-        imp::reset_stdin(imp::as_raw(&*self.original))
+        let mut stdin = self.read_ahead.as_ref().map(|_| io::stdin().lock());
+        // What was read ahead from the override goes with it. The stream is reset even if that
+        // fails, and what was read ahead from the original can't be put back then.
+        let taken = stdin.as_mut().map(readahead::take).transpose();
+        if let Err(e) = imp::reset_stdin(imp::as_raw(&*self.original)) {
+            // The override stays, so it keeps what it read ahead.
+            if let (Some(stdin), Ok(Some(bytes))) = (&mut stdin, &taken) {
+                let _ = readahead::inject(stdin, bytes);
+            }
+            return Err(e);
+        }
+        OVERRIDDEN_STDIN_COUNT.store(self.index, Ordering::SeqCst);
+        registry::unregister(Stream::Stdin, self.index);
+        let (Some(stdin), Some(read_ahead)) = (&mut stdin, &self.read_ahead) else {
+            return Ok(());
+        };
+        taken.and_then(|_| readahead::inject(stdin, read_ahead)).map_err(|e| match read_ahead.len() {
+            0 => e,
+            lost => io::Error::new(e.kind(), format!("{} bytes read ahead from the original standard input were lost: {}", lost, e)),
+        })
     }
 }
 impl Read for StdinOverride {
@@ -162,7 +326,7 @@ impl Read for StdinOverride {
         self.original.read_vectored(bufs)
     }
 }
-impl<'a> Read for &'a StdinOverride {
+impl Read for &StdinOverride {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         (&*self.original).read(buf)
     }
@@ -186,56 +350,130 @@ static OVERRIDDEN_STDOUT_COUNT: AtomicUsize = AtomicUsize::new(0);
 pub struct StdoutOverride {
     original: ManuallyDrop<File>,
     index: usize,
+    held: Option<lock::Held>,
+    #[cfg(unix)]
+    generation: usize,
+    relay: Option<relay::RelayHandle>,
 }
 impl StdoutOverride {
+    #[track_caller]
     fn from_raw_inner(raw: imp::Raw, owned: bool) -> io::Result<Self> {
+        let held = lock::acquire("StdoutOverride")?;
+        let original = imp::override_stdout(raw, owned)?;
+        let index = OVERRIDDEN_STDOUT_COUNT.fetch_add(1, Ordering::SeqCst);
+        registry::register(Stream::Stdout, index, imp::as_raw(&original));
         Ok(Self {
-            original: ManuallyDrop::new(imp::override_stdout(raw, owned)?),
-            index: OVERRIDDEN_STDOUT_COUNT.fetch_add(1, Ordering::SeqCst),
+            original: ManuallyDrop::new(original),
+            index,
+            held,
+            #[cfg(unix)]
+            generation: fork::generation(),
+            relay: None,
         })
     }
     /// Redirect standard output to the raw file descriptor or handle. It must be writable.
     ///
     /// The stream is not owned, so it is your job to close it later. Closing it while this exists
     /// will not close the standard output.
+    #[track_caller]
     pub fn from_raw(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, false)
     }
     /// Redirect standard output to the owned raw file descriptor or handle. It must be writable.
     ///
     /// The stream is owned, and so you must not use it after passing it to this function.
+    #[track_caller]
     pub fn from_raw_owned(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, true)
     }
     /// Redirect standard output to the IO device. The device must be writable.
     ///
     /// Dropping the IO device after calling this function will not close the standard output.
+    #[track_caller]
     pub fn from_io_ref<T: imp::AsRaw>(io: &T) -> io::Result<Self> {
         Self::from_raw(imp::as_raw(io))
     }
     /// Redirect standard output to the IO device. The device must be writable.
+    #[track_caller]
     pub fn from_io<T: imp::IntoRaw>(io: T) -> io::Result<Self> {
         Self::from_raw_owned(imp::into_raw(io))
     }
     /// Redirect the standard output to the file at that file path.
     ///
     /// The file will be created if it does not exist, and will be truncated if it does.
+    #[track_caller]
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         Self::from_io(File::create(path)?)
     }
+    /// Redirect the standard output to a new pipe, and return the pipe's read end.
+    ///
+    /// The guard owns the write end, so the reader sees the end of the file once the guard is reset.
+    #[track_caller]
+    pub fn pipe() -> io::Result<(Self, PipeReader)> {
+        let (reader, writer) = os_pipe::pipe()?;
+        Ok((Self::from_io(writer)?, reader))
+    }
+    /// Redirect the standard output through a pipe to the relay.
+    ///
+    /// The relay's thread is joined when this is reset, and any error it hit writing to its sink is
+    /// returned then.
+    #[track_caller]
+    pub fn from_relay(relay: Relay) -> io::Result<Self> {
+        let (mut guard, reader) = Self::pipe()?;
+        guard.relay = Some(relay.tee_to(&guard.original)?.spawn(reader)?);
+        Ok(guard)
+    }
+    /// What the relay has done so far, if the standard output was redirected to one.
+    pub fn relay_stats(&self) -> Option<RelayStats> {
+        self.relay.as_ref().map(relay::RelayHandle::stats)
+    }
+    /// Redirect the standard output to all of the sinks.
+    ///
+    /// This is a shorthand for [`from_relay`](Self::from_relay) with [`Relay::fan_out`].
+    #[track_caller]
+    pub fn fan_out(sinks: Vec<Sink>) -> io::Result<Self> {
+        Self::from_relay(Relay::fan_out(sinks))
+    }
+    /// The original standard output, for a child process that should write to it instead of
+    /// the override. Children spawned while the override is active inherit the override otherwise,
+    /// unless a fork policy set with `set_fork_policy` applies to them.
+    pub fn original_for_child(&self) -> io::Result<process::Stdio> {
+        Ok(self.original.try_clone()?.into())
+    }
     /// Reset the standard output to its state before this type was constructed.
     ///
     /// This can be called to manually handle errors produced by the destructor.
-    pub fn reset(self) -> io::Result<()> {
-        self.reset_inner()?;
-        std::mem::forget(self);
-        Ok(())
+    pub fn reset(mut self) -> io::Result<()> {
+        let res = self.reset_inner();
+        mem::forget(self);
+        res
     }
-    fn reset_inner(&self) -> io::Result<()> {
-        if OVERRIDDEN_STDOUT_COUNT.swap(self.index, Ordering::SeqCst) <= self.index {
+    fn reset_inner(&mut self) -> io::Result<()> {
+        // Released once the stream is reset.
+        let _held = self.held.take();
+        #[cfg(unix)]
+        if self.generation != fork::generation() {
+            // The override was made before a fork, so it belongs to the parent.
+            // Its threads are the parent's.
+            mem::forget(self.relay.take());
+            return imp::close(imp::as_raw(&*self.original));
+        }
+        if OVERRIDDEN_STDOUT_COUNT.load(Ordering::SeqCst) <= self.index {
             panic!("Stdout override reset out of order!");
         }
-        imp::reset_stdout(imp::as_raw(&*self.original))
+        if let Err(e) = imp::reset_stdout(imp::as_raw(&*self.original)) {
+            // The stream still goes into the pipe, so the relay is left running to drain it, and the
+            // override stays registered for the exit and fork handlers to restore.
+            drop(self.relay.take());
+            return Err(e);
+        }
+        OVERRIDDEN_STDOUT_COUNT.store(self.index, Ordering::SeqCst);
+        registry::unregister(Stream::Stdout, self.index);
+        // Resetting closed the last copy of the pipe's write end, so the relay will see the end.
+        match self.relay.take() {
+            Some(relay) => relay.join(),
+            None => Ok(()),
+        }
     }
 }
 impl Write for StdoutOverride {
@@ -249,7 +487,7 @@ impl Write for StdoutOverride {
         self.original.flush()
     }
 }
-impl<'a> Write for &'a StdoutOverride {
+impl Write for &StdoutOverride {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         (&*self.original).write(buf)
     }
@@ -276,56 +514,130 @@ static OVERRIDDEN_STDERR_COUNT: AtomicUsize = AtomicUsize::new(0);
 pub struct StderrOverride {
     original: ManuallyDrop<File>,
     index: usize,
+    held: Option<lock::Held>,
+    #[cfg(unix)]
+    generation: usize,
+    relay: Option<relay::RelayHandle>,
 }
 impl StderrOverride {
+    #[track_caller]
     fn from_raw_inner(raw: imp::Raw, owned: bool) -> io::Result<Self> {
+        let held = lock::acquire("StderrOverride")?;
+        let original = imp::override_stderr(raw, owned)?;
+        let index = OVERRIDDEN_STDERR_COUNT.fetch_add(1, Ordering::SeqCst);
+        registry::register(Stream::Stderr, index, imp::as_raw(&original));
         Ok(Self {
-            original: ManuallyDrop::new(imp::override_stderr(raw, owned)?),
-            index: OVERRIDDEN_STDERR_COUNT.fetch_add(1, Ordering::SeqCst),
+            original: ManuallyDrop::new(original),
+            index,
+            held,
+            #[cfg(unix)]
+            generation: fork::generation(),
+            relay: None,
         })
     }
     /// Redirect standard error to the raw file descriptor or handle. It must be writable.
     ///
     /// The stream is not owned, so it is your job to close it later. Closing it while this exists
     /// will not close the standard error.
+    #[track_caller]
     pub fn from_raw(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, false)
     }
     /// Redirect standard error to the owned raw file descriptor or handle. It must be writable.
     ///
     /// The stream is owned, and so you must not use it after passing it to this function.
+    #[track_caller]
     pub fn from_raw_owned(raw: imp::Raw) -> io::Result<Self> {
         Self::from_raw_inner(raw, true)
     }
     /// Redirect standard error to the IO device. The device must be writable.
     ///
     /// Dropping the IO device after calling this function will not close the standard error.
+    #[track_caller]
     pub fn from_io_ref<T: imp::AsRaw>(io: &T) -> io::Result<Self> {
         Self::from_raw(imp::as_raw(io))
     }
     /// Redirect standard error to the IO device. The device must be writable.
+    #[track_caller]
     pub fn from_io<T: imp::IntoRaw>(io: T) -> io::Result<Self> {
         Self::from_raw_owned(imp::into_raw(io))
     }
     /// Redirect the standard error to the file at that file path.
     ///
     /// The file will be created if it does not exist, and will be truncated if it does.
+    #[track_caller]
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         Self::from_io(File::create(path)?)
     }
+    /// Redirect the standard error to a new pipe, and return the pipe's read end.
+    ///
+    /// The guard owns the write end, so the reader sees the end of the file once the guard is reset.
+    #[track_caller]
+    pub fn pipe() -> io::Result<(Self, PipeReader)> {
+        let (reader, writer) = os_pipe::pipe()?;
+        Ok((Self::from_io(writer)?, reader))
+    }
+    /// Redirect the standard error through a pipe to the relay.
+    ///
+    /// The relay's thread is joined when this is reset, and any error it hit writing to its sink is
+    /// returned then.
+    #[track_caller]
+    pub fn from_relay(relay: Relay) -> io::Result<Self> {
+        let (mut guard, reader) = Self::pipe()?;
+        guard.relay = Some(relay.tee_to(&guard.original)?.spawn(reader)?);
+        Ok(guard)
+    }
+    /// What the relay has done so far, if the standard error was redirected to one.
+    pub fn relay_stats(&self) -> Option<RelayStats> {
+        self.relay.as_ref().map(relay::RelayHandle::stats)
+    }
+    /// Redirect the standard error to all of the sinks.
+    ///
+    /// This is a shorthand for [`from_relay`](Self::from_relay) with [`Relay::fan_out`].
+    #[track_caller]
+    pub fn fan_out(sinks: Vec<Sink>) -> io::Result<Self> {
+        Self::from_relay(Relay::fan_out(sinks))
+    }
+    /// The original standard error, for a child process that should write to it instead of
+    /// the override. Children spawned while the override is active inherit the override otherwise,
+    /// unless a fork policy set with `set_fork_policy` applies to them.
+    pub fn original_for_child(&self) -> io::Result<process::Stdio> {
+        Ok(self.original.try_clone()?.into())
+    }
     /// Reset the standard error to its state before this type was constructed.
     ///
     /// This can be called to manually handle errors produced by the destructor.
-    pub fn reset(self) -> io::Result<()> {
-        self.reset_inner()?;
-        std::mem::forget(self);
-        Ok(())
+    pub fn reset(mut self) -> io::Result<()> {
+        let res = self.reset_inner();
+        mem::forget(self);
+        res
     }
-    fn reset_inner(&self) -> io::Result<()> {
-        if OVERRIDDEN_STDERR_COUNT.swap(self.index, Ordering::SeqCst) <= self.index {
+    fn reset_inner(&mut self) -> io::Result<()> {
+        // Released once the stream is reset.
+        let _held = self.held.take();
+        #[cfg(unix)]
+        if self.generation != fork::generation() {
+            // The override was made before a fork, so it belongs to the parent.
+            // Its threads are the parent's.
+            mem::forget(self.relay.take());
+            return imp::close(imp::as_raw(&*self.original));
+        }
+        if OVERRIDDEN_STDERR_COUNT.load(Ordering::SeqCst) <= self.index {
             panic!("Stderr override reset out of order!");
         }
-        imp::reset_stderr(imp::as_raw(&*self.original))
+        if let Err(e) = imp::reset_stderr(imp::as_raw(&*self.original)) {
+            // The stream still goes into the pipe, so the relay is left running to drain it, and the
+            // override stays registered for the exit and fork handlers to restore.
+            drop(self.relay.take());
+            return Err(e);
+        }
+        OVERRIDDEN_STDERR_COUNT.store(self.index, Ordering::SeqCst);
+        registry::unregister(Stream::Stderr, self.index);
+        // Resetting closed the last copy of the pipe's write end, so the relay will see the end.
+        match self.relay.take() {
+            Some(relay) => relay.join(),
+            None => Ok(()),
+        }
     }
 }
 impl Write for StderrOverride {
@@ -339,7 +651,7 @@ impl Write for StderrOverride {
         self.original.flush()
     }
 }
-impl<'a> Write for &'a StderrOverride {
+impl Write for &StderrOverride {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         (&*self.original).write(buf)
     }
@@ -433,6 +745,240 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_pipes() -> Result<()> {
+        let (guard, mut stdout) = StdoutOverride::pipe()?;
+        print!("through the pipe");
+        io::stdout().flush()?;
+        guard.reset()?;
+        let mut contents = String::new();
+        stdout.read_to_string(&mut contents)?;
+        assert_eq!("through the pipe", contents);
+
+        let (guard, mut stderr) = StderrOverride::pipe()?;
+        eprint!("to stderr");
+        drop(guard);
+        let mut contents = String::new();
+        stderr.read_to_string(&mut contents)?;
+        assert_eq!("to stderr", contents);
+
+        let (guard, mut stdin) = StdinOverride::pipe()?;
+        writeln!(stdin, "typed")?;
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        assert_eq!("typed\n", line);
+        guard.reset()?;
+        assert_eq!(io::ErrorKind::BrokenPipe, writeln!(stdin, "too late").unwrap_err().kind());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stdin_read_ahead() -> Result<()> {
+        // The handoff is process-wide, so it is only enabled in a child.
+        match unsafe { libc::fork() } {
+            -1 => Err(std::io::Error::last_os_error()),
+            0 => {
+                enable_read_ahead_handoff();
+                let res = std::panic::catch_unwind(|| -> Result<()> {
+                    let (outer, mut outer_input) = StdinOverride::pipe()?;
+                    write!(outer_input, "outer 1\nouter 2\n")?;
+                    let mut line = String::new();
+                    stdin().read_line(&mut line)?;
+                    assert_eq!("outer 1\n", line);
+
+                    // "outer 2" is in std's buffer, but isn't read from the inner override.
+                    let (inner, mut inner_input) = StdinOverride::pipe()?;
+                    write!(inner_input, "inner 1\ninner 2\n")?;
+                    line.clear();
+                    stdin().read_line(&mut line)?;
+                    assert_eq!("inner 1\n", line);
+
+                    // "inner 2" is dropped with the inner override, and "outer 2" comes back.
+                    inner.reset()?;
+                    line.clear();
+                    stdin().read_line(&mut line)?;
+                    assert_eq!("outer 2\n", line);
+                    drop(outer_input);
+                    line.clear();
+                    assert_eq!(0, stdin().read_line(&mut line)?);
+                    outer.reset()
+                });
+                unsafe { libc::_exit(if matches!(res, Ok(Ok(()))) { 0 } else { 101 }) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_stdin_with_blocked_reader() -> Result<()> {
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let (outer, mut outer_input) = StdinOverride::pipe()?;
+        let reader = thread::spawn(|| {
+            let mut line = String::new();
+            stdin().read_line(&mut line).map(|_| line)
+        });
+        // Gives the reader time to block, holding the lock on `io::stdin`.
+        thread::sleep(Duration::from_millis(50));
+
+        // Neither overriding nor resetting waits for the reader.
+        let overridden = thread::spawn(|| StdinOverride::pipe().and_then(|(inner, _input)| inner.reset()));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !overridden.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let finished = overridden.is_finished();
+        writeln!(outer_input, "unblocked")?;
+        assert_eq!("unblocked\n", reader.join().unwrap()?);
+        assert!(finished, "overriding standard input waited for a blocked reader");
+        overridden.join().unwrap()?;
+        outer.reset()
+    }
+
+    #[test]
+    fn test_stdout_relay() -> Result<()> {
+        let (mut rx, tx) = pipe()?;
+
+        let relay = Relay::new(tx).transform(transform::Pipeline::new().prefix("> "));
+        let guard = StdoutOverride::from_relay(relay)?;
+        println!("relayed");
+        print!("unterminated");
+        stdout().flush()?;
+        guard.reset()?;
+
+        let mut contents = String::new();
+        rx.read_to_string(&mut contents)?;
+        assert_eq!("> relayed\n> unterminated", contents);
+
+        Ok(())
+    }
+
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stderr_fan_out() -> Result<()> {
+        let (mut rx_a, tx_a) = pipe()?;
+        let (mut rx_b, tx_b) = pipe()?;
+
+        let sinks =
+            vec![tx_a.into(), Sink::new(Broken).on_error(ErrorPolicy::DropSink), Sink::new(tx_b).on_error(ErrorPolicy::Retry(3))];
+        let guard = StderrOverride::fan_out(sinks)?;
+        eprintln!("to both");
+        guard.reset()?;
+
+        let (mut a, mut b) = (String::new(), String::new());
+        rx_a.read_to_string(&mut a)?;
+        rx_b.read_to_string(&mut b)?;
+        assert_eq!("to both\n", a);
+        assert_eq!("to both\n", b);
+
+        let guard = StderrOverride::fan_out(vec![Broken.into()])?;
+        eprintln!("lost");
+        assert_eq!(std::io::ErrorKind::BrokenPipe, guard.reset().unwrap_err().kind());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_reset() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        use std::sync::mpsc::{channel, Sender};
+        use std::time::Duration;
+
+        struct Channel(Sender<Vec<u8>>);
+        impl Write for Channel {
+            fn write(&mut self, buf: &[u8]) -> Result<usize> {
+                let _ = self.0.send(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        // Standard output can't be put back, so this runs in a child.
+        match unsafe { libc::fork() } {
+            -1 => Err(std::io::Error::last_os_error()),
+            0 => {
+                let res = std::panic::catch_unwind(|| {
+                    let (tx, rx) = channel();
+                    let guard = StdoutOverride::from_relay(Relay::new(Channel(tx))).unwrap();
+                    let index = guard.index;
+                    unsafe { libc::close(guard.as_raw_fd()) };
+                    assert!(guard.reset().is_err());
+
+                    // The relay still drains the pipe, and the override is still registered.
+                    println!("still relayed");
+                    stdout().flush().unwrap();
+                    assert_eq!(b"still relayed\n".to_vec(), rx.recv_timeout(Duration::from_secs(10)).unwrap());
+                    assert!(registry::is_registered(Stream::Stdout, index));
+
+                    // Likewise, standard input still reads from the override, read ahead included.
+                    let (guard, mut writer) = StdinOverride::pipe().unwrap();
+                    let index = guard.index;
+                    writer.write_all(b"read ahead\nafter\n").unwrap();
+                    let mut line = String::new();
+                    stdin().read_line(&mut line).unwrap();
+                    unsafe { libc::close(guard.as_raw_fd()) };
+                    assert!(guard.reset().is_err());
+                    drop(writer);
+                    line.clear();
+                    stdin().read_line(&mut line).unwrap();
+                    assert_eq!("after\n", line);
+                    assert!(registry::is_registered(Stream::Stdin, index));
+                    assert_eq!(index + 1, OVERRIDDEN_STDIN_COUNT.load(std::sync::atomic::Ordering::SeqCst));
+                });
+                unsafe { libc::_exit(if res.is_ok() { 0 } else { 101 }) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_original_for_child() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        use std::process::Command;
+
+        let (mut rx, tx) = pipe()?;
+        let outer = StdoutOverride::from_io(tx)?;
+        let inner = StdoutOverride::from_io(null()?)?;
+        assert_eq!(libc::FD_CLOEXEC, unsafe { libc::fcntl(inner.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC);
+
+        Command::new("echo").arg("overridden").status()?;
+        Command::new("echo").arg("original").stdout(inner.original_for_child()?).status()?;
+        inner.reset()?;
+        outer.reset()?;
+
+        let mut contents = String::new();
+        rx.read_to_string(&mut contents)?;
+        assert_eq!("original\n", contents);
+
+        Ok(())
+    }
+
     fn null() -> Result<File> {
         File::create(if cfg!(windows) {
             "nul"
@@ -443,6 +989,31 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_reset_closes_original() -> Result<()> {
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        // The inner overrides save the outer overrides' pipes, which only end once they are closed.
+        let (mut rx, tx) = pipe()?;
+        let outer = StdoutOverride::from_io(tx)?;
+        let inner = StdoutOverride::from_io(null()?)?;
+        inner.reset()?;
+        outer.reset()?;
+        let (done, ended) = channel();
+        std::thread::spawn(move || done.send(rx.read_to_end(&mut Vec::new()).map(drop)));
+        assert!(ended.recv_timeout(Duration::from_secs(10)).expect("the pipe was left open").is_ok());
+
+        let (rx, mut tx) = pipe()?;
+        let outer = StdinOverride::from_io(rx)?;
+        let inner = StdinOverride::from_io(null()?)?;
+        inner.reset()?;
+        outer.reset()?;
+        assert_eq!(std::io::ErrorKind::BrokenPipe, tx.write(b"unread").unwrap_err().kind());
+
+        Ok(())
+    }
+
     #[test]
     fn test_multiple() -> Result<()> {
         let null = null()?;
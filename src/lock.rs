@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::spin::SpinLock;
+
+/// How long to wait for the lock in milliseconds, or zero if it isn't used.
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+static OWNER: SpinLock<Option<Owner>> = SpinLock::new(None);
+
+/// The thread holding the lock, and the first guard it took it for.
+struct Owner {
+    thread: ThreadId,
+    thread_name: Option<String>,
+    guard: &'static str,
+    location: &'static Location<'static>,
+    depth: usize,
+}
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a {} made at {} on thread ", self.guard, self.location)?;
+        match &self.thread_name {
+            Some(name) => write!(f, "'{}'", name),
+            None => write!(f, "{:?}", self.thread),
+        }
+    }
+}
+
+/// Make every override take a process-wide lock, held until it is reset.
+///
+/// Overrides made on different threads then wait for each other instead of corrupting each other,
+/// so tests that override the standard streams can run in parallel without `--test-threads=1`.
+/// The lock is reentrant, so a thread can still nest overrides.
+///
+/// A constructor that waits longer than `timeout` fails with [`io::ErrorKind::TimedOut`], and
+/// the error says which guard holds the lock and where it was made. Deadlocks aren't detected, so
+/// the timeout is the only thing that ends a wait for a guard that was leaked, or for one whose
+/// thread is waiting on the waiting thread.
+///
+/// Output printed with `print!` still goes to the test harness's capture unless tests are run with
+/// `--nocapture`.
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use stdio_override::{enable_override_lock, Capture};
+///
+/// enable_override_lock(Duration::from_secs(30));
+/// let capture = Capture::stdout()?;
+/// println!("no other thread can override stdout until this is finished");
+/// assert_eq!("no other thread can override stdout until this is finished\n", capture.finish()?.stdout);
+/// # Ok(())
+/// # }
+/// ```
+pub fn enable_override_lock(timeout: Duration) {
+    let timeout_ms = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX).max(1);
+    TIMEOUT_MS.store(timeout_ms, Ordering::SeqCst);
+}
+
+/// A hold on the override lock, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Held {
+    #[cfg(unix)]
+    generation: usize,
+}
+impl Drop for Held {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if self.generation != crate::fork::generation() {
+            return;
+        }
+        let mut owner = OWNER.lock();
+        if let Some(current) = owner.as_mut() {
+            current.depth -= 1;
+            if current.depth == 0 {
+                *owner = None;
+            }
+        }
+    }
+}
+
+/// Take the lock for a `guard`, if it is enabled.
+#[track_caller]
+pub(crate) fn acquire(guard: &'static str) -> io::Result<Option<Held>> {
+    let timeout = match TIMEOUT_MS.load(Ordering::SeqCst) {
+        0 => return Ok(None),
+        timeout_ms => Duration::from_millis(timeout_ms),
+    };
+    let location = Location::caller();
+    let deadline = Instant::now() + timeout;
+    let thread = thread::current();
+    loop {
+        {
+            let mut owner = OWNER.lock();
+            match owner.as_mut() {
+                None => {
+                    let thread_name = thread.name().map(str::to_owned);
+                    *owner = Some(Owner { thread: thread.id(), thread_name, guard, location, depth: 1 });
+                    return Ok(Some(held()));
+                }
+                Some(current) if current.thread == thread.id() => {
+                    current.depth += 1;
+                    return Ok(Some(held()));
+                }
+                Some(current) if Instant::now() >= deadline => {
+                    let message = format!("timed out after {:?} waiting for the override lock, held by {}", timeout, current);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+                }
+                Some(_) => {}
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn held() -> Held {
+    Held {
+        #[cfg(unix)]
+        generation: crate::fork::generation(),
+    }
+}
+
+/// Release the lock in a forked child, where only the forking thread is left. Its holds are stale.
+#[cfg(unix)]
+pub(crate) fn after_fork() {
+    unsafe { OWNER.force_unlock() };
+    // Freeing the stale owner's thread name isn't async-signal-safe, so it is leaked instead.
+    std::mem::forget(OWNER.lock().take());
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::StdoutOverride;
+
+    #[test]
+    fn test_override_lock() {
+        // Leaked guards would hold the lock forever, so it is only enabled in a child.
+        match unsafe { libc::fork() } {
+            -1 => panic!("{}", io::Error::last_os_error()),
+            0 => {
+                enable_override_lock(Duration::from_millis(50));
+                let ok = (|| {
+                    let guard = StdoutOverride::from_file("/dev/null").ok()?;
+                    let nested = StdoutOverride::from_file("/dev/null").ok()?;
+                    let error = thread::spawn(|| StdoutOverride::from_file("/dev/null").err()).join().ok()??;
+                    let reported = error.kind() == io::ErrorKind::TimedOut
+                        && error.to_string().contains("StdoutOverride made at src/lock.rs")
+                        && error.to_string().contains("'lock::test::test_override_lock'");
+                    drop(nested);
+                    drop(guard);
+                    let after = thread::spawn(|| StdoutOverride::from_file("/dev/null").map(drop).is_ok()).join().ok()?;
+                    Some(reported && after)
+                })();
+                unsafe { libc::_exit(if ok == Some(true) { 0 } else { 1 }) };
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+}
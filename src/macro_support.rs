@@ -0,0 +1,60 @@
+//! What the code generated by the [`capture`](crate::capture) attribute calls.
+
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::thread;
+
+use crate::{rust_print_is_captured, Capture, Captured};
+
+/// A captured test's capture. If the test panics before it is finished, what was captured is
+/// written to the original streams, so it isn't lost.
+pub struct TestCapture {
+    capture: Option<Capture>,
+    print_is_captured: bool,
+}
+impl Deref for TestCapture {
+    type Target = Capture;
+    fn deref(&self) -> &Capture {
+        self.capture.as_ref().expect("the capture is only taken when finishing")
+    }
+}
+impl Drop for TestCapture {
+    fn drop(&mut self) {
+        if let Some(capture) = self.capture.take() {
+            let _ = capture.replay();
+        }
+    }
+}
+
+#[track_caller]
+pub fn start() -> TestCapture {
+    let print_is_captured = match rust_print_is_captured() {
+        Ok(captured) => captured,
+        Err(e) => panic!("failed to capture the test's output: {}", e),
+    };
+    match Capture::options().flush_c_stdio(true).start() {
+        Ok(capture) => TestCapture { capture: Some(capture), print_is_captured },
+        Err(e) => panic!("failed to capture the test's output: {}", e),
+    }
+}
+
+/// Finish the capture, returning what was captured if the test's `stdout` and `stderr` arguments
+/// can be checked against it.
+///
+/// They can't when `print!` is captured by the test harness, as the output printed with it never
+/// reaches the capture. A note saying the arguments weren't checked is written instead.
+pub fn finish(mut capture: TestCapture, checks: bool) -> Option<Captured> {
+    let captured = match capture.capture.take().expect("a capture is only finished once").finish() {
+        Ok(captured) => captured,
+        Err(e) => panic!("failed to finish capturing the test's output: {}", e),
+    };
+    if !capture.print_is_captured {
+        return Some(captured);
+    }
+    if checks {
+        let name = thread::current().name().unwrap_or("a captured test").to_owned();
+        // Not eprintln!, which the harness would capture too.
+        let _ = writeln!(io::stderr(), "note: {} didn't check its output, as print! is captured; run it with --nocapture", name);
+    }
+    None
+}
@@ -0,0 +1,108 @@
+use std::env;
+
+use regex::Regex;
+
+use crate::Captured;
+
+/// Replaces the parts of captured output that change from run to run with placeholders, so the
+/// output can be compared exactly.
+///
+/// [`Normalizer::default`] has all the built-in rules, and [`Normalizer::new`] none of them. Rules
+/// are applied in the order they were added. Needs the `normalize` feature.
+/// ```rust
+/// use stdio_override::{Captured, Normalizer};
+///
+/// let captured = Captured {
+///     stdout: "2024-05-01T12:00:00Z started in 15.2ms at 0x7ffd5e8c\n".into(),
+///     ..Captured::default()
+/// };
+/// let normalizer = Normalizer::default().rule(r"started", "began").unwrap();
+/// assert_eq!("[TIMESTAMP] began in [DURATION] at [ADDRESS]\n", captured.normalize(&normalizer).stdout);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    rules: Vec<(Regex, String)>,
+}
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self::new().timestamps().temp_paths().hex_addresses().durations()
+    }
+}
+impl Normalizer {
+    /// A normalizer with no rules.
+    pub fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+    /// Replace everything matching the regular expression with `placeholder`, which can refer to
+    /// capture groups as `$1` or `${name}`.
+    pub fn rule(mut self, pattern: &str, placeholder: &str) -> Result<Self, regex::Error> {
+        self.rules.push((Regex::new(pattern)?, placeholder.to_owned()));
+        Ok(self)
+    }
+    fn builtin(self, pattern: &str, placeholder: &str) -> Self {
+        self.rule(pattern, placeholder).expect("built-in rules are valid")
+    }
+    /// Replace ISO 8601 timestamps, like `2024-05-01T12:00:00.123+02:00`, with `[TIMESTAMP]`.
+    pub fn timestamps(self) -> Self {
+        self.builtin(r"\b\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?", "[TIMESTAMP]")
+    }
+    /// Replace paths in `/tmp` or the system's temporary directory with `[TEMP]`.
+    pub fn temp_paths(self) -> Self {
+        let mut dirs = vec![regex::escape("/tmp")];
+        if let Some(temp_dir) = env::temp_dir().to_str() {
+            dirs.push(regex::escape(temp_dir.trim_end_matches(['/', '\\'])));
+        }
+        self.builtin(&format!(r#"(?:{})(?:[/\\][^\s'"`]*)?"#, dirs.join("|")), "[TEMP]")
+    }
+    /// Replace hexadecimal addresses, like `0x7ffd5e8c`, with `[ADDRESS]`.
+    pub fn hex_addresses(self) -> Self {
+        self.builtin(r"\b0x[0-9a-fA-F]+\b", "[ADDRESS]")
+    }
+    /// Replace durations, like `15.2ms` or `3s`, with `[DURATION]`.
+    pub fn durations(self) -> Self {
+        self.builtin(r"\b\d+(?:\.\d+)?(?:ns|µs|us|ms|s|min|m|h)\b", "[DURATION]")
+    }
+    /// Apply the rules to `text`.
+    pub fn normalize(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for (regex, placeholder) in &self.rules {
+            text = regex.replace_all(&text, placeholder.as_str()).into_owned();
+        }
+        text
+    }
+}
+
+impl Captured {
+    /// The captured output with the normalizer applied to both streams.
+    pub fn normalize(&self, normalizer: &Normalizer) -> Captured {
+        Captured { stdout: normalizer.normalize(&self.stdout), stderr: normalizer.normalize(&self.stderr), ..self.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_rules() {
+        let normalizer = Normalizer::default();
+        assert_eq!(
+            "at [TIMESTAMP] and [TIMESTAMP]: wrote [TEMP] in [DURATION], [DURATION] and [DURATION]; ptr [ADDRESS]\n",
+            normalizer.normalize(
+                "at 2024-05-01T12:00:00Z and 2024-05-01 12:00:00.123+02:00: wrote /tmp/.tmpA1b2/out.txt in 15.2ms, 3s and 120µs; ptr 0x7ffd5e8c\n"
+            )
+        );
+        assert_eq!("1st of 5 items, 0xg", normalizer.normalize("1st of 5 items, 0xg"));
+    }
+
+    #[test]
+    fn test_user_rules() -> Result<(), regex::Error> {
+        let normalizer = Normalizer::new().rule(r"pid (\d+)", "pid [PID]")?.rule(r"user=(?P<name>\w+)", "user=<${name}>")?;
+        let captured = Captured { stdout: "pid 4242 user=root\n".into(), stderr: "took 3s\n".into(), ..Captured::default() };
+        let normalized = captured.normalize(&normalizer);
+        assert_eq!("pid [PID] user=<root>\n", normalized.stdout);
+        assert_eq!("took 3s\n", normalized.stderr);
+        assert!(Normalizer::new().rule("(", "").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Printing that is never intercepted by the test harness.
+//!
+//! When tests run without `--nocapture`, the test harness captures `print!` and friends in a buffer
+//! of its own, so their output never reaches an override. The macros here write to [`io::stdout`]
+//! and [`io::stderr`] directly, which the harness doesn't intercept.
+//! ```rust
+//! # fn main() -> std::io::Result<()> {
+//! use stdio_override::{print::println, Capture};
+//!
+//! let capture = Capture::stdout()?;
+//! println!("always captured");
+//! assert_eq!("always captured\n", capture.finish()?.stdout);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::StdoutOverride;
+
+/// Like [`std::eprint!`], but never intercepted by the test harness, so it always reaches
+/// overrides of the standard error.
+pub use crate::__stdio_override_eprint as eprint;
+/// Like [`std::eprintln!`], but never intercepted by the test harness, so it always reaches
+/// overrides of the standard error.
+pub use crate::__stdio_override_eprintln as eprintln;
+/// Like [`std::print!`], but never intercepted by the test harness, so it always reaches overrides
+/// of the standard output.
+pub use crate::__stdio_override_print as print;
+/// Like [`std::println!`], but never intercepted by the test harness, so it always reaches
+/// overrides of the standard output.
+pub use crate::__stdio_override_println as println;
+
+/// What [`rust_print_is_captured`] prints: a zero width space, which is invisible if it does end up
+/// in the test harness's output.
+const PROBE: &str = "\u{200b}";
+
+/// Whether `print!` on this thread is intercepted before it reaches the standard output.
+///
+/// When tests run without `--nocapture`, the test harness captures `print!`, `println!`, `eprint!`
+/// and `eprintln!` in a buffer of its own, so their output never reaches an override. This probes for
+/// that by briefly overriding the standard output and printing an invisible character. Writes made
+/// with [`io::stdout`] and the macros in [`print`](crate::print) are never intercepted.
+///
+/// The harness captures per thread, so this only says whether prints on the calling thread are
+/// intercepted. Anything printed by other threads while probing is lost.
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// if stdio_override::rust_print_is_captured()? {
+///     eprintln!("run the tests with --nocapture");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn rust_print_is_captured() -> io::Result<bool> {
+    let (mut reader, writer) = os_pipe::pipe()?;
+    io::stdout().flush()?;
+    let guard = StdoutOverride::from_io(writer)?;
+    std::print!("{}", PROBE);
+    let flushed = io::stdout().flush();
+    guard.reset()?;
+    flushed?;
+
+    let mut probe = Vec::new();
+    reader.read_to_end(&mut probe)?;
+    Ok(probe != PROBE.as_bytes())
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if let Err(e) = io::stdout().write_fmt(args) {
+        panic!("failed printing to stdout: {}", e);
+    }
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    if let Err(e) = io::stderr().write_fmt(args) {
+        panic!("failed printing to stderr: {}", e);
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __stdio_override_print {
+    ($($arg:tt)*) => {
+        $crate::print::_print(format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __stdio_override_println {
+    () => {
+        $crate::print::_print(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::print::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __stdio_override_eprint {
+    ($($arg:tt)*) => {
+        $crate::print::_eprint(format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __stdio_override_eprintln {
+    () => {
+        $crate::print::_eprint(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::print::_eprint(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Capture;
+
+    #[test]
+    fn test_macros_reach_capture() -> io::Result<()> {
+        // The tests are run with --nocapture.
+        assert!(!rust_print_is_captured()?);
+
+        let capture = Capture::options().strict(true).start()?;
+        super::print!("{}-", 1);
+        super::println!("{}", 2);
+        super::println!();
+        super::eprintln!("to {}", "stderr");
+        let captured = capture.finish()?;
+        assert_eq!("1-2\n\n", captured.stdout);
+        assert_eq!("to stderr\n", captured.stderr);
+        Ok(())
+    }
+}
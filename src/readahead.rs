@@ -0,0 +1,65 @@
+//! Moving what std's standard input has read ahead across overrides.
+//!
+//! [`io::stdin`] reads through a buffer, so it can hold bytes read from one source after standard
+//! input is switched to another. These read the buffer without reading more from the stream, and
+//! fill it with bytes of our choosing, by briefly pointing standard input at a pipe.
+
+use std::io::{self, BufRead, StdinLock, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::imp;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Keep what [`io::stdin`] reads ahead from crossing [`StdinOverride`](crate::StdinOverride)s.
+///
+/// `io::stdin` reads ahead into a buffer, so by default what it read from the original standard
+/// input before an override is read before the override's, and what it read ahead from the
+/// override is still read after the override is reset. Once this is called, overrides made
+/// afterwards set aside what was read from the original while they are active and put it back
+/// afterwards, and drop what was read ahead from them when they are reset.
+///
+/// That needs the lock on `io::stdin`, so overriding and resetting then wait for any thread
+/// blocked reading standard input through it. Standard input also briefly reads from a pipe of our
+/// own while the buffer is moved, which code reading it without `io::stdin`, like C code or child
+/// processes, could see.
+pub fn enable_read_ahead_handoff() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether overrides made now hand off what `io::stdin` reads ahead.
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Run `f` with standard input temporarily reading from `source`.
+fn with_stdin_from<T: imp::IntoRaw, R>(source: T, f: impl FnOnce() -> R) -> io::Result<R> {
+    let original = imp::override_stdin(imp::into_raw(source), true)?;
+    let res = f();
+    imp::reset_stdin(imp::into_raw(original))?;
+    Ok(res)
+}
+
+/// Take the bytes std's standard input has buffered, without reading any more.
+pub(crate) fn take(stdin: &mut StdinLock) -> io::Result<Vec<u8>> {
+    // With the buffer empty, this reads the end of the file straight away.
+    let (empty, writer) = os_pipe::pipe()?;
+    drop(writer);
+    with_stdin_from(empty, || {
+        let buffered = stdin.fill_buf()?.to_vec();
+        stdin.consume(buffered.len());
+        Ok(buffered)
+    })?
+}
+
+/// Make std's standard input return `bytes` before it reads any more. Its buffer must be empty, and
+/// `bytes` must fit in it, as they do when they were taken from it.
+pub(crate) fn inject(stdin: &mut StdinLock, bytes: &[u8]) -> io::Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let (reader, mut writer) = os_pipe::pipe()?;
+    writer.write_all(bytes)?;
+    drop(writer);
+    with_stdin_from(reader, || stdin.fill_buf().map(drop))?
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::mem::ManuallyDrop;
+
+use crate::imp;
+use crate::spin::{SpinLock, SpinLockGuard};
+
+/// One of the standard streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+/// An active override, and the original stream it saved.
+#[derive(Debug)]
+struct Entry {
+    stream: Stream,
+    index: usize,
+    original: imp::Raw,
+}
+// Raw handles are only ever used while the registry is locked, and the guard that owns one
+// unregisters it before closing it.
+unsafe impl Send for Entry {}
+
+static ACTIVE: SpinLock<Vec<Entry>> = SpinLock::new(Vec::new());
+
+fn lock() -> SpinLockGuard<'static, Vec<Entry>> {
+    ACTIVE.lock()
+}
+
+pub(crate) fn register(stream: Stream, index: usize, original: imp::Raw) {
+    lock().push(Entry { stream, index, original });
+}
+
+/// Unregister an override, along with any overrides of the same stream made after it. Those can
+/// only still be registered if their guards were leaked.
+pub(crate) fn unregister(stream: Stream, index: usize) {
+    lock().retain(|entry| entry.stream != stream || entry.index < index);
+}
+
+/// Put back the original streams saved by the outermost active overrides, including those of
+/// leaked guards. The guards themselves are left alone.
+#[cfg(unix)]
+pub(crate) fn restore_all() {
+    let active = lock();
+    for stream in [Stream::Stdin, Stream::Stdout, Stream::Stderr] {
+        if let Some(entry) = active.iter().filter(|entry| entry.stream == stream).min_by_key(|entry| entry.index) {
+            let _ = imp::restore(stream, entry.original);
+        }
+    }
+}
+
+/// Forget every override in a forked child, first passing the outermost original of each stream to
+/// `f`.
+///
+/// # Safety
+/// This must only be called in a forked child before it has started any threads.
+#[cfg(unix)]
+pub(crate) unsafe fn after_fork(mut f: impl FnMut(Stream, imp::Raw)) {
+    ACTIVE.force_unlock();
+    let mut active = lock();
+    for stream in [Stream::Stdin, Stream::Stdout, Stream::Stderr] {
+        if let Some(entry) = active.iter().filter(|entry| entry.stream == stream).min_by_key(|entry| entry.index) {
+            f(stream, entry.original);
+        }
+    }
+    active.clear();
+}
+
+/// A copy of the original stream saved by the outermost active override of `stream`, if there is
+/// one and it can be copied. Only the copying is done with the registry locked.
+pub(crate) fn outermost(stream: Stream) -> Option<File> {
+    let active = lock();
+    let entry = active.iter().filter(|entry| entry.stream == stream).min_by_key(|entry| entry.index)?;
+    let original = ManuallyDrop::new(unsafe { imp::file_from_raw(entry.original) });
+    original.try_clone().ok()
+}
+
+/// Whether the override of `stream` made with `index` is active, and no later one of it is.
+pub(crate) fn is_innermost(stream: Stream, index: usize) -> bool {
+    lock().iter().filter(|entry| entry.stream == stream).map(|entry| entry.index).max() == Some(index)
+}
+
+#[cfg(all(test, unix))]
+pub(crate) fn is_registered(stream: Stream, index: usize) -> bool {
+    lock().iter().any(|entry| entry.stream == stream && entry.index == index)
+}
@@ -0,0 +1,727 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use os_pipe::PipeReader;
+
+use crate::transform::{Pipeline, Transform};
+
+const CHUNK_SIZE: usize = 8 * 1024;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A destination for an overridden output stream, fed through a pipe.
+///
+/// The stream is redirected to the write end of a pipe, and a background thread drains the read end,
+/// passes the bytes through the relay's transforms and writes the result to the sink. The thread is
+/// joined when the override is reset, so by then everything written to the stream has reached the
+/// sink. The exception is a relay with a [`Backpressure`] policy other than `Block` whose sink
+/// doesn't catch up within its [`drain_timeout`](Self::drain_timeout): what is still queued is
+/// dropped and resetting fails with [`io::ErrorKind::TimedOut`], rather than waiting for the sink.
+///
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::{transform::Pipeline, Relay, StdoutOverride};
+/// use std::{fs::{self, File}, io::{self, Write}};
+/// let file_name = "./relay.txt";
+///
+/// let pipeline = Pipeline::new().filter(|line| !line.contains("noise")).prefix("[worker-3] ");
+/// let guard = StdoutOverride::from_relay(Relay::new(File::create(file_name)?).transform(pipeline))?;
+/// println!("some noise");
+/// println!("Hello!");
+/// io::stdout().flush()?;
+/// guard.reset()?;
+///
+/// assert_eq!("[worker-3] Hello!\n", fs::read_to_string(file_name)?);
+/// # fs::remove_file(file_name)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Relay {
+    sink: Box<dyn Write + Send>,
+    pipeline: Pipeline,
+    backpressure: Backpressure,
+    drain_timeout: Duration,
+    tee: bool,
+}
+impl Relay {
+    /// Create a relay that writes everything to the sink unchanged.
+    pub fn new<W: Write + Send + 'static>(sink: W) -> Self {
+        Self {
+            sink: Box::new(sink),
+            pipeline: Pipeline::new(),
+            backpressure: Backpressure::Block,
+            drain_timeout: DRAIN_TIMEOUT,
+            tee: false,
+        }
+    }
+    /// Create a relay that writes everything to all of the sinks.
+    ///
+    /// Each sink's [`ErrorPolicy`] decides what happens when writing to it fails.
+    pub fn fan_out(sinks: Vec<Sink>) -> Self {
+        Self::new(FanOut { sinks })
+    }
+    /// Add a transform to the end of the relay's pipeline.
+    pub fn transform<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.pipeline = self.pipeline.then(transform);
+        self
+    }
+    /// Set what happens when the sink is slower than the stream. This is [`Backpressure::Block`] by
+    /// default.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+    /// Set how long resetting the override waits for the sink to take what is still queued, when
+    /// the [`Backpressure`] policy queues output. This is five seconds by default. With
+    /// [`Backpressure::Block`] resetting always waits for the sink.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+    /// Also write everything, after the transforms, to where the stream went before it was
+    /// overridden. If that fails the relay carries on with just its sink.
+    pub fn tee(mut self) -> Self {
+        self.tee = true;
+        self
+    }
+    /// Add the stream's original destination as a sink if the relay tees.
+    pub(crate) fn tee_to(mut self, original: &File) -> io::Result<Self> {
+        if self.tee {
+            let original = Sink::new(original.try_clone()?).on_error(ErrorPolicy::DropSink);
+            let sink = Sink { writer: self.sink, policy: ErrorPolicy::Fail };
+            self.sink = Box::new(FanOut { sinks: vec![original, sink] });
+        }
+        Ok(self)
+    }
+    pub(crate) fn spawn(self, source: PipeReader) -> io::Result<RelayHandle> {
+        let Self { sink, pipeline, backpressure, drain_timeout, .. } = self;
+        let stats = Arc::new(Counters::default());
+        let (output, writer) = match backpressure {
+            Backpressure::Block => (Output::Sink(Some(sink)), None),
+            backpressure => {
+                let queue = Arc::new(Queue::new(backpressure)?);
+                let (queue_ref, stats_ref) = (Arc::clone(&queue), Arc::clone(&stats));
+                let writer = spawn_named("stdio-override relay writer", move || drain(&queue_ref, sink, &stats_ref))?;
+                (Output::Queue(Arc::clone(&queue)), Some((writer, queue)))
+            }
+        };
+        let stats_ref = Arc::clone(&stats);
+        let reader = spawn_named("stdio-override relay", move || run(source, pipeline, output, &stats_ref))?;
+        Ok(RelayHandle { reader, writer, stats, drain_timeout })
+    }
+}
+impl fmt::Debug for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Relay")
+            .field("pipeline", &self.pipeline)
+            .field("backpressure", &self.backpressure)
+            .field("drain_timeout", &self.drain_timeout)
+            .field("tee", &self.tee)
+            .finish()
+    }
+}
+
+/// The number of relay threads that haven't finished yet.
+static RUNNING_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+fn spawn_named<F: FnOnce() -> io::Result<()> + Send + 'static>(name: &str, f: F) -> io::Result<JoinHandle<io::Result<()>>> {
+    struct Running;
+    impl Drop for Running {
+        fn drop(&mut self) {
+            RUNNING_THREADS.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+    RUNNING_THREADS.fetch_add(1, Ordering::AcqRel);
+    let running = Running;
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let _running = running;
+        f()
+    })
+}
+
+/// Forget the relay threads in a forked child, where they don't exist.
+#[cfg(unix)]
+pub(crate) fn forget_threads() {
+    RUNNING_THREADS.store(0, Ordering::SeqCst);
+}
+
+/// Wait until every relay thread has finished, or until the timeout passes. Returns whether they
+/// all finished.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn wait_for_all(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while RUNNING_THREADS.load(Ordering::Acquire) > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// What a relay does when its sink is slower than the stream being relayed.
+///
+/// With any policy other than `Block` the pipe is drained into a queue by one thread and the queue is
+/// written to the sink by another, so a stalled sink never stalls the writers of the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Stop draining the pipe until the sink catches up. Once the pipe is full, writes to the stream
+    /// block.
+    #[default]
+    Block,
+    /// Queue up to this many bytes, and drop new output while the queue is full.
+    DropNewest(usize),
+    /// Queue up to this many bytes, and drop the oldest queued output to make room for new output.
+    DropOldest(usize),
+    /// Queue up to this many bytes in memory, and queue the rest in a temporary file.
+    SpillToDisk(usize),
+}
+
+/// Counters of what a relay did with the bytes it read, after they went through its transforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelayStats {
+    /// Bytes written to the sink.
+    pub relayed: u64,
+    /// Bytes dropped by the [`Backpressure`] policy.
+    pub dropped: u64,
+    /// Bytes that were queued on disk by [`Backpressure::SpillToDisk`].
+    pub spilled: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    relayed: AtomicU64,
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+}
+impl Counters {
+    fn add(counter: &AtomicU64, len: usize) {
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+enum Output {
+    Sink(Option<Box<dyn Write + Send>>),
+    Queue(Arc<Queue>),
+}
+
+/// A queue of relayed output waiting to be written to a slow sink.
+///
+/// Only the relay thread pushes and only the writer thread pops, so each side of the spill file has
+/// a single user, and its reads and writes are done without holding the lock on the queue.
+struct Queue {
+    policy: Backpressure,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    spill: Option<Spill>,
+}
+struct QueueState {
+    chunks: VecDeque<Vec<u8>>,
+    len: usize,
+    /// Where the spill file has been written up to.
+    spill_written: u64,
+    /// Where the spill file has been read up to.
+    spill_read: u64,
+    /// Whether the writer thread is reading from the spill file.
+    spill_reading: bool,
+    closed: bool,
+}
+impl QueueState {
+    fn spill_pending(&self) -> u64 {
+        self.spill_written - self.spill_read
+    }
+}
+impl Queue {
+    fn new(policy: Backpressure) -> io::Result<Self> {
+        let spill = if let Backpressure::SpillToDisk(_) = policy { Some(Spill::new()?) } else { None };
+        let state =
+            QueueState { chunks: VecDeque::new(), len: 0, spill_written: 0, spill_read: 0, spill_reading: false, closed: false };
+        Ok(Self { policy, state: Mutex::new(state), ready: Condvar::new(), spill })
+    }
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn push(&self, mut chunk: Vec<u8>, stats: &Counters) -> io::Result<()> {
+        let mut state = self.lock();
+        match self.policy {
+            Backpressure::Block => unreachable!("blocking relays write straight to the sink"),
+            Backpressure::DropNewest(capacity) => {
+                let room = capacity.saturating_sub(state.len);
+                if chunk.len() > room {
+                    Counters::add(&stats.dropped, chunk.len() - room);
+                    chunk.truncate(room);
+                }
+            }
+            Backpressure::DropOldest(capacity) => {
+                if chunk.len() > capacity {
+                    Counters::add(&stats.dropped, chunk.len() - capacity);
+                    chunk.drain(..chunk.len() - capacity);
+                }
+                while state.len + chunk.len() > capacity {
+                    let excess = state.len + chunk.len() - capacity;
+                    let front = state.chunks.front_mut().expect("the queue is over capacity, so it is not empty");
+                    let dropped = excess.min(front.len());
+                    front.drain(..dropped);
+                    if front.is_empty() {
+                        state.chunks.pop_front();
+                    }
+                    state.len -= dropped;
+                    Counters::add(&stats.dropped, dropped);
+                }
+            }
+            // Once anything is on disk, everything after it has to go there too to keep the order.
+            Backpressure::SpillToDisk(capacity) if state.spill_pending() > 0 || state.len + chunk.len() > capacity => {
+                return self.spill(state, &chunk, stats);
+            }
+            Backpressure::SpillToDisk(_) => {}
+        }
+        if !chunk.is_empty() {
+            state.len += chunk.len();
+            state.chunks.push_back(chunk);
+        }
+        self.ready.notify_one();
+        Ok(())
+    }
+    /// Append the chunk to the spill file, writing it with the queue unlocked.
+    fn spill(&self, mut state: MutexGuard<'_, QueueState>, chunk: &[u8], stats: &Counters) -> io::Result<()> {
+        let spill = self.spill.as_ref().expect("spilling queues have a spill file");
+        // Everything written has been read, so the file can start over.
+        let truncate = state.spill_pending() == 0 && !state.spill_reading && state.spill_written > 0;
+        if truncate {
+            state.spill_written = 0;
+            state.spill_read = 0;
+        }
+        let offset = state.spill_written;
+        drop(state);
+
+        let res = spill.write_at(offset, chunk, truncate);
+        let mut state = self.lock();
+        match res {
+            Ok(()) => {
+                state.spill_written += chunk.len() as u64;
+                Counters::add(&stats.spilled, chunk.len());
+            }
+            Err(_) => Counters::add(&stats.dropped, chunk.len()),
+        }
+        self.ready.notify_one();
+        res
+    }
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_one();
+    }
+    /// Drop everything queued, and stop the writer thread once it is done with what it is writing.
+    fn abandon(&self, stats: &Counters) {
+        let mut state = self.lock();
+        Counters::add(&stats.dropped, state.len + state.spill_pending() as usize);
+        state.chunks.clear();
+        state.len = 0;
+        state.spill_read = state.spill_written;
+        state.closed = true;
+        self.ready.notify_one();
+    }
+    /// Wait for the next chunk, or `None` once the queue is closed and empty.
+    fn pop(&self, stats: &Counters) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.lock();
+        loop {
+            if let Some(chunk) = state.chunks.pop_front() {
+                state.len -= chunk.len();
+                return Ok(Some(chunk));
+            }
+            if state.spill_pending() > 0 {
+                match self.unspill(state, stats)? {
+                    Some(chunk) => return Ok(Some(chunk)),
+                    // The queue was abandoned while the chunk was read.
+                    None => state = self.lock(),
+                }
+                continue;
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = self.ready.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+    /// Read the next chunk from the spill file, reading it with the queue unlocked. Returns `None` if
+    /// the queue was abandoned in the meantime.
+    fn unspill(&self, mut state: MutexGuard<'_, QueueState>, stats: &Counters) -> io::Result<Option<Vec<u8>>> {
+        let spill = self.spill.as_ref().expect("only spilling queues have anything spilled");
+        let offset = state.spill_read;
+        let mut chunk = vec![0; state.spill_pending().min(CHUNK_SIZE as u64) as usize];
+        state.spill_reading = true;
+        drop(state);
+
+        let res = spill.read_at(offset, &mut chunk);
+        let mut state = self.lock();
+        state.spill_reading = false;
+        match res {
+            Ok(()) if state.spill_read == offset => {
+                state.spill_read += chunk.len() as u64;
+                Ok(Some(chunk))
+            }
+            Ok(()) => Ok(None),
+            Err(e) => {
+                Counters::add(&stats.dropped, state.spill_pending() as usize);
+                state.spill_read = state.spill_written;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A temporary file used as the tail of a [`Queue`].
+///
+/// It is opened twice, so that writing it and reading it don't share a file offset.
+struct Spill {
+    path: PathBuf,
+    writer: Mutex<File>,
+    reader: Mutex<File>,
+}
+impl Spill {
+    fn new() -> io::Result<Self> {
+        static SPILLS: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("stdio-override-spill-{}-{}", process::id(), SPILLS.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        let writer = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let spill = Self { reader: Mutex::new(File::open(&path)?), writer: Mutex::new(writer), path };
+        Ok(spill)
+    }
+    fn write_at(&self, offset: u64, chunk: &[u8], truncate: bool) -> io::Result<()> {
+        let mut file = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if truncate {
+            file.set_len(0)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(chunk)
+    }
+    fn read_at(&self, offset: u64, chunk: &mut [u8]) -> io::Result<()> {
+        let mut file = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(chunk)
+    }
+}
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// What a fanned out relay does when writing to one of its sinks fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Fail the whole relay. Nothing more is written to any sink, and the error is returned when the
+    /// override is reset.
+    Fail,
+    /// Stop writing to this sink, and carry on with the others.
+    DropSink,
+    /// Retry the write up to this many times, and then fail the whole relay.
+    Retry(u32),
+}
+
+/// One destination of a fanned out relay.
+pub struct Sink {
+    writer: Box<dyn Write + Send>,
+    policy: ErrorPolicy,
+}
+impl Sink {
+    /// A sink that fails the relay if writing to it fails.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self { writer: Box::new(writer), policy: ErrorPolicy::Fail }
+    }
+    /// Set what happens when writing to this sink fails.
+    pub fn on_error(mut self, policy: ErrorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let mut failures = 0;
+        while !buf.is_empty() {
+            match self.writer.write(buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => buf = &buf[len..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => self.failed(e, &mut failures)?,
+            }
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let mut failures = 0;
+        loop {
+            match self.writer.flush() {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => self.failed(e, &mut failures)?,
+            }
+        }
+    }
+    fn failed(&self, e: io::Error, failures: &mut u32) -> io::Result<()> {
+        match self.policy {
+            ErrorPolicy::Retry(retries) if *failures < retries => {
+                *failures += 1;
+                thread::sleep(RETRY_DELAY);
+                Ok(())
+            }
+            _ => Err(e),
+        }
+    }
+}
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sink").field("policy", &self.policy).finish()
+    }
+}
+impl<W: Write + Send + 'static> From<W> for Sink {
+    fn from(writer: W) -> Self {
+        Self::new(writer)
+    }
+}
+
+struct FanOut {
+    sinks: Vec<Sink>,
+}
+impl FanOut {
+    fn each(&mut self, mut op: impl FnMut(&mut Sink) -> io::Result<()>) -> io::Result<()> {
+        let mut error = None;
+        self.sinks.retain_mut(|sink| match op(sink) {
+            Ok(()) => true,
+            Err(_) if sink.policy == ErrorPolicy::DropSink => false,
+            Err(e) => {
+                error.get_or_insert(e);
+                true
+            }
+        });
+        error.map_or(Ok(()), Err)
+    }
+}
+impl Write for FanOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.each(|sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(Sink::flush)
+    }
+}
+
+/// The running relay threads of an override.
+pub(crate) struct RelayHandle {
+    /// The thread draining the pipe.
+    reader: JoinHandle<io::Result<()>>,
+    /// The thread writing the queue to the sink, if the relay has one.
+    writer: Option<(JoinHandle<io::Result<()>>, Arc<Queue>)>,
+    stats: Arc<Counters>,
+    drain_timeout: Duration,
+}
+// The handle is only ever joined, which is sound no matter where a panic happened.
+impl UnwindSafe for RelayHandle {}
+impl RefUnwindSafe for RelayHandle {}
+impl RelayHandle {
+    pub(crate) fn stats(&self) -> RelayStats {
+        RelayStats {
+            relayed: self.stats.relayed.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            spilled: self.stats.spilled.load(Ordering::Relaxed),
+        }
+    }
+    /// Wait for the relay to reach the end of the stream. The write end of the pipe must be closed
+    /// first.
+    ///
+    /// A queued relay's writer is only waited for until the drain timeout. If it is still writing by
+    /// then, what is left in the queue is dropped and the writer is left to finish on its own.
+    pub(crate) fn join(self) -> io::Result<()> {
+        let res = join_thread(self.reader);
+        let (writer, queue) = match self.writer {
+            Some(writer) => writer,
+            None => return res,
+        };
+        let deadline = Instant::now() + self.drain_timeout;
+        while !writer.is_finished() {
+            if Instant::now() >= deadline {
+                queue.abandon(&self.stats);
+                return res.and(Err(io::Error::new(io::ErrorKind::TimedOut, "the relay's sink didn't catch up in time")));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        res.and(join_thread(writer))
+    }
+}
+impl fmt::Debug for RelayHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RelayHandle").field("stats", &self.stats()).field("drain_timeout", &self.drain_timeout).finish()
+    }
+}
+
+fn join_thread(thread: JoinHandle<io::Result<()>>) -> io::Result<()> {
+    thread.join().unwrap_or_else(|_| Err(io::Error::other("relay thread panicked")))
+}
+
+fn run(mut source: PipeReader, mut pipeline: Pipeline, mut output: Output, stats: &Counters) -> io::Result<()> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut transformed = Vec::new();
+    let mut res = Ok(());
+    loop {
+        let len = match source.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                res = res.and(Err(e));
+                break;
+            }
+        };
+        transformed.clear();
+        pipeline.transform(&chunk[..len], &mut transformed);
+        let chunk_res = output.write(&transformed, stats);
+        res = res.and(chunk_res);
+    }
+    transformed.clear();
+    pipeline.finish(&mut transformed);
+    let finish_res = output.write(&transformed, stats);
+    if let Output::Queue(queue) = &output {
+        queue.close();
+    }
+    res.and(finish_res)
+}
+
+impl Output {
+    fn write(&mut self, transformed: &[u8], stats: &Counters) -> io::Result<()> {
+        match self {
+            // After the sink fails nothing more is written to it, but the pipe is still drained.
+            Output::Sink(Some(sink)) => write_out(sink, transformed, stats).inspect_err(|_| *self = Output::Sink(None)),
+            Output::Sink(None) => Ok(()),
+            Output::Queue(queue) if !transformed.is_empty() => queue.push(transformed.to_vec(), stats),
+            Output::Queue(_) => Ok(()),
+        }
+    }
+}
+
+/// Write the queued output to the sink until the queue is closed.
+fn drain(queue: &Queue, mut sink: Box<dyn Write + Send>, stats: &Counters) -> io::Result<()> {
+    // After the sink fails the queue is still drained, and its memory freed.
+    let mut res = Ok(());
+    loop {
+        match queue.pop(stats) {
+            Ok(Some(chunk)) if res.is_ok() => res = write_out(&mut sink, &chunk, stats),
+            Ok(Some(_)) => {}
+            Ok(None) => return res,
+            Err(e) => res = res.and(Err(e)),
+        }
+    }
+}
+
+fn write_out(sink: &mut dyn Write, output: &[u8], stats: &Counters) -> io::Result<()> {
+    if !output.is_empty() {
+        sink.write_all(output)?;
+        Counters::add(&stats.relayed, output.len());
+    }
+    sink.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn pop_all(queue: &Queue, stats: &Counters) -> Vec<Vec<u8>> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop(stats).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_queue_policies() -> io::Result<()> {
+        let stats = Counters::default();
+        let queue = Queue::new(Backpressure::DropNewest(4))?;
+        queue.push(b"abc".to_vec(), &stats)?;
+        queue.push(b"def".to_vec(), &stats)?;
+        assert_eq!(vec![b"abc".to_vec(), b"d".to_vec()], pop_all(&queue, &stats));
+        assert_eq!(2, stats.dropped.load(Ordering::Relaxed));
+
+        let stats = Counters::default();
+        let queue = Queue::new(Backpressure::DropOldest(4))?;
+        queue.push(b"abc".to_vec(), &stats)?;
+        queue.push(b"def".to_vec(), &stats)?;
+        assert_eq!(vec![b"c".to_vec(), b"def".to_vec()], pop_all(&queue, &stats));
+        assert_eq!(2, stats.dropped.load(Ordering::Relaxed));
+
+        let stats = Counters::default();
+        let queue = Queue::new(Backpressure::SpillToDisk(4))?;
+        queue.push(b"abc".to_vec(), &stats)?;
+        queue.push(b"def".to_vec(), &stats)?;
+        queue.push(b"g".to_vec(), &stats)?;
+        assert_eq!(vec![b"abc".to_vec(), b"defg".to_vec()], pop_all(&queue, &stats));
+        assert_eq!(4, stats.spilled.load(Ordering::Relaxed));
+        assert_eq!(0, stats.dropped.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    /// A sink that blocks until it is released.
+    struct Stalled(Receiver<()>);
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stalled_sink_does_not_block() -> io::Result<()> {
+        let (release, stalled) = channel();
+        let (reader, mut writer) = os_pipe::pipe()?;
+        let relay = Relay::new(Stalled(stalled)).backpressure(Backpressure::DropOldest(1024)).spawn(reader)?;
+
+        let data = vec![b'x'; 1024 * 1024];
+        writer.write_all(&data)?;
+        drop(writer);
+        drop(release);
+
+        let counters = Arc::clone(&relay.stats);
+        relay.join()?;
+        let (relayed, dropped) = (counters.relayed.load(Ordering::Relaxed), counters.dropped.load(Ordering::Relaxed));
+        assert!(dropped > 0);
+        assert_eq!(data.len() as u64, relayed + dropped);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stalled_sink_does_not_block_reset() -> io::Result<()> {
+        for backpressure in [Backpressure::DropNewest(1024), Backpressure::DropOldest(1024), Backpressure::SpillToDisk(1024)] {
+            let (release, stalled) = channel();
+            let (reader, mut writer) = os_pipe::pipe()?;
+            let relay =
+                Relay::new(Stalled(stalled)).backpressure(backpressure).drain_timeout(Duration::from_millis(50)).spawn(reader)?;
+
+            let data = vec![b'x'; 64 * 1024];
+            writer.write_all(&data)?;
+            drop(writer);
+
+            let counters = Arc::clone(&relay.stats);
+            let start = Instant::now();
+            assert_eq!(io::ErrorKind::TimedOut, relay.join().unwrap_err().kind());
+            assert!(start.elapsed() < Duration::from_secs(5));
+            // The sink is still stuck in its first write, and everything after it was dropped.
+            assert_eq!(0, counters.relayed.load(Ordering::Relaxed));
+            assert!(counters.dropped.load(Ordering::Relaxed) > 0);
+
+            drop(release);
+            while counters.relayed.load(Ordering::Relaxed) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let (relayed, dropped) = (counters.relayed.load(Ordering::Relaxed), counters.dropped.load(Ordering::Relaxed));
+            assert_eq!(data.len() as u64, relayed + dropped, "{:?}", backpressure);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::Captured;
+
+/// The environment variable that makes snapshot assertions rewrite the snapshots instead.
+const UPDATE_VAR: &str = "STDIO_OVERRIDE_UPDATE";
+/// The environment variable that overrides the default snapshot directory.
+const DIR_VAR: &str = "STDIO_OVERRIDE_SNAPSHOT_DIR";
+
+/// How many unchanged lines to show around each change in a diff.
+const CONTEXT: usize = 3;
+/// The most entries the table of common subsequences in a diff can have.
+const MAX_TABLE_SIZE: usize = 1 << 22;
+
+/// A directory of snapshots: files holding the expected output of a test.
+///
+/// [`Snapshots::default`] uses the directory named by `STDIO_OVERRIDE_SNAPSHOT_DIR`, or else
+/// `tests/snapshots` in the package being tested. Setting `STDIO_OVERRIDE_UPDATE=1` makes every
+/// assertion write the actual output to its snapshot and pass, which is how snapshots are created
+/// and updated.
+/// ```rust,no_run
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::{Capture, Snapshots};
+/// # #[cfg(feature = "normalize")]
+/// use stdio_override::Normalizer;
+///
+/// let capture = Capture::stdout()?;
+/// println!("Hello, world!");
+/// let captured = capture.finish()?;
+///
+/// captured.assert_matches_snapshot("hello");
+/// // Timestamps, temporary paths and the like can be normalized away first.
+/// # #[cfg(feature = "normalize")]
+/// captured.normalize(&Normalizer::default()).assert_matches_snapshot("hello-normalized");
+/// Snapshots::new("tests/golden").assert_matches("hello", &captured.stdout);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshots {
+    dir: PathBuf,
+    update: bool,
+}
+impl Default for Snapshots {
+    fn default() -> Self {
+        let dir = match env::var_os(DIR_VAR) {
+            Some(dir) => PathBuf::from(dir),
+            None => env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default().join("tests").join("snapshots"),
+        };
+        let update = env::var_os(UPDATE_VAR).is_some_and(|update| update != "0" && !update.is_empty());
+        Self { dir, update }
+    }
+}
+impl Snapshots {
+    /// Use the snapshots in `dir`. Whether to update them still comes from `STDIO_OVERRIDE_UPDATE`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into(), ..Self::default() }
+    }
+    /// Whether assertions rewrite the snapshots instead of comparing against them.
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+    /// The file the snapshot called `name` is stored in.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.snap", name))
+    }
+    /// Compare `actual` against the snapshot called `name`, or rewrite the snapshot when updating.
+    ///
+    /// # Panics
+    /// If they differ, with a line diff of the two, or if the snapshot can't be read or written.
+    #[track_caller]
+    pub fn assert_matches(&self, name: &str, actual: &str) {
+        if let Err(message) = self.check(name, actual) {
+            panic!("{}", message);
+        }
+    }
+    fn check(&self, name: &str, actual: &str) -> Result<(), String> {
+        let path = self.path(name);
+        if self.update {
+            return fs::create_dir_all(&self.dir)
+                .and_then(|()| fs::write(&path, actual))
+                .map_err(|e| format!("failed to write snapshot {}: {}", path.display(), e));
+        }
+        match fs::read_to_string(&path) {
+            Ok(expected) if expected == actual => Ok(()),
+            Ok(expected) => Err(format!(
+                "output doesn't match snapshot {} (run with {}=1 to update it)\n{}",
+                path.display(),
+                UPDATE_VAR,
+                diff(&expected, actual)
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(format!("snapshot {} doesn't exist (run with {}=1 to create it)", path.display(), UPDATE_VAR))
+            }
+            Err(e) => Err(format!("failed to read snapshot {}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl Captured {
+    /// Compare the captured standard output against the snapshot called `name` in the default
+    /// [`Snapshots`] directory.
+    ///
+    /// # Panics
+    /// If they differ, with a line diff of the two, or if the snapshot can't be read or written.
+    #[track_caller]
+    pub fn assert_matches_snapshot(&self, name: &str) {
+        Snapshots::default().assert_matches(name, &self.stdout);
+    }
+    /// Compare the captured standard error against the snapshot called `name` in the default
+    /// [`Snapshots`] directory.
+    ///
+    /// # Panics
+    /// If they differ, with a line diff of the two, or if the snapshot can't be read or written.
+    #[track_caller]
+    pub fn assert_stderr_matches_snapshot(&self, name: &str) {
+        Snapshots::default().assert_matches(name, &self.stderr);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A line diff from `expected` to `actual`, using their longest common subsequence of lines.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    // Usually only a few lines in the middle differ, so only those go through the table.
+    let prefix = expected.iter().zip(&actual).take_while(|(e, a)| e == a).count();
+    let suffix = expected[prefix..].iter().rev().zip(actual[prefix..].iter().rev()).take_while(|(e, a)| e == a).count();
+    let mut lines: Vec<Line> = expected[..prefix].iter().map(|&line| Line::Same(line)).collect();
+    diff_middle(&expected[prefix..expected.len() - suffix], &actual[prefix..actual.len() - suffix], &mut lines);
+    lines.extend(expected[expected.len() - suffix..].iter().map(|&line| Line::Same(line)));
+
+    let mut shown = vec![false; lines.len()];
+    for (i, _) in lines.iter().enumerate().filter(|(_, line)| !matches!(line, Line::Same(_))) {
+        let end = (i + CONTEXT).min(lines.len() - 1);
+        shown[i.saturating_sub(CONTEXT)..=end].iter_mut().for_each(|shown| *shown = true);
+    }
+    let mut output = String::from("--- snapshot\n+++ actual\n");
+    let mut skipping = false;
+    for (i, line) in lines.iter().enumerate() {
+        if !shown[i] {
+            if !skipping {
+                output.push_str("@@ ... @@\n");
+                skipping = true;
+            }
+            continue;
+        }
+        skipping = false;
+        let _ = match line {
+            Line::Same(line) => writeln!(output, " {}", line),
+            Line::Removed(line) => writeln!(output, "-{}", line),
+            Line::Added(line) => writeln!(output, "+{}", line),
+        };
+    }
+    if !shown.contains(&true) {
+        output.push_str("(the snapshot and output differ only in line endings)\n");
+    }
+    output
+}
+
+/// Diff lines that differ at both ends. If the table would be too big, every expected line is
+/// shown as removed and every actual line as added.
+fn diff_middle<'a>(expected: &[&'a str], actual: &[&'a str], lines: &mut Vec<Line<'a>>) {
+    if (expected.len() + 1).saturating_mul(actual.len() + 1) > MAX_TABLE_SIZE {
+        lines.extend(expected.iter().map(|&line| Line::Removed(line)));
+        lines.extend(actual.iter().map(|&line| Line::Added(line)));
+        return;
+    }
+    // common[i][j] is the length of the longest common subsequence of expected[i..] and actual[j..].
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(Line::Same(expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(Line::Removed(expected[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(actual[j]));
+            j += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let expected = "a\nb\nc\nd\ne\nf\ng\nh\ni\n";
+        let actual = "a\nb\nc\nd\nE\nf\ng\nh\ni\nj\n";
+        assert_eq!("--- snapshot\n+++ actual\n@@ ... @@\n b\n c\n d\n-e\n+E\n f\n g\n h\n i\n+j\n", diff(expected, actual));
+        assert!(diff("a\nb\nc\nd\ne\n", "a\nb\nc\nd\nX\n").starts_with("--- snapshot\n+++ actual\n@@ ... @@\n b\n"));
+
+        // Long outputs with a change in the middle, or nothing in common, are diffed quickly.
+        let expected: String = (0..100_000).map(|i| format!("{}\n", i)).collect();
+        let actual = expected.replace("\n50000\n", "\nchanged\n");
+        assert_eq!(
+            "--- snapshot\n+++ actual\n@@ ... @@\n 49997\n 49998\n 49999\n-50000\n+changed\n 50001\n 50002\n 50003\n@@ ... @@\n",
+            diff(&expected, &actual)
+        );
+        let actual: String = (0..100_000).map(|i| format!("other {}\n", i)).collect();
+        assert_eq!(200_002, diff(&expected, &actual).lines().count());
+    }
+
+    #[test]
+    fn test_snapshots() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("stdio-override-snapshots-{}", std::process::id()));
+        let snapshots = Snapshots::new(&dir).update(false);
+        assert!(snapshots.check("hello", "hello\n").unwrap_err().contains("doesn't exist"));
+
+        snapshots.clone().update(true).assert_matches("hello", "hello\n");
+        assert_eq!("hello\n", fs::read_to_string(snapshots.path("hello"))?);
+        snapshots.assert_matches("hello", "hello\n");
+        let error = snapshots.check("hello", "goodbye\n").unwrap_err();
+        assert!(error.ends_with("-hello\n+goodbye\n"), "{}", error);
+
+        fs::remove_dir_all(&dir)
+    }
+}
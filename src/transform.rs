@@ -0,0 +1,439 @@
+//! Transforms applied to the output of a [`Relay`](crate::Relay).
+//!
+//! A [`Transform`] works on the raw chunks of bytes drained from the pipe, which may split lines
+//! and multi-byte sequences at arbitrary points. Most transforms only care about whole lines, so
+//! they implement [`LineTransform`] instead and are wrapped in [`Lines`], which reassembles the
+//! lines before handing them over.
+//!
+//! A [`Pipeline`] chains transforms together:
+//! ```rust
+//! use stdio_override::transform::Pipeline;
+//!
+//! let pipeline = Pipeline::new()
+//!     .filter(|line| !line.starts_with("DEBUG"))
+//!     .map(|line| line.replace("/home/user", "~"))
+//!     .prefix("[worker-3] ");
+//! ```
+
+use std::borrow::Cow;
+#[cfg(feature = "redact")]
+use std::env;
+#[cfg(feature = "redact")]
+use std::ffi::OsStr;
+use std::fmt;
+#[cfg(feature = "redact")]
+use std::io;
+use std::mem;
+
+#[cfg(feature = "redact")]
+use regex::bytes::{NoExpand, Regex};
+
+pub use crate::ansi::{Ansi, AnsiMode};
+
+/// A streaming transformation of relayed bytes.
+pub trait Transform: Send {
+    /// Transform a chunk of input, appending the result to `output`.
+    ///
+    /// Chunks are split wherever the pipe reads happened to end, so anything that spans chunks
+    /// must be held back until the rest of it arrives.
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>);
+    /// Append anything still held back to `output`. This is called once, at the end of the stream.
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        let _ = output;
+    }
+}
+
+/// A transformation of whole lines. Wrap it in [`Lines`] to use it as a [`Transform`].
+pub trait LineTransform: Send {
+    /// Transform a single line, given without its line terminator. Returning `None` drops the line.
+    ///
+    /// The last line of the stream is passed here too if it is not terminated.
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// Adapts a [`LineTransform`] into a [`Transform`] by splitting the input into lines.
+///
+/// Both `\n` and `\r\n` terminators are recognized, and are restored after the line is transformed.
+#[derive(Debug)]
+pub struct Lines<T> {
+    inner: T,
+    partial: Vec<u8>,
+}
+impl<T: LineTransform> Lines<T> {
+    /// Split input into lines for the line transform.
+    pub fn new(inner: T) -> Self {
+        Self { inner, partial: Vec::new() }
+    }
+    fn emit(&mut self, terminated: bool, output: &mut Vec<u8>) {
+        let mut line = mem::take(&mut self.partial);
+        let terminator: &[u8] = if !terminated {
+            b""
+        } else if line.last() == Some(&b'\r') {
+            line.pop();
+            b"\r\n"
+        } else {
+            b"\n"
+        };
+        if let Some(line) = self.inner.line(line) {
+            output.extend_from_slice(&line);
+            output.extend_from_slice(terminator);
+        }
+    }
+}
+impl<T: LineTransform> Transform for Lines<T> {
+    fn transform(&mut self, mut input: &[u8], output: &mut Vec<u8>) {
+        while let Some(end) = input.iter().position(|&b| b == b'\n') {
+            self.partial.extend_from_slice(&input[..end]);
+            self.emit(true, output);
+            input = &input[end + 1..];
+        }
+        self.partial.extend_from_slice(input);
+    }
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        if !self.partial.is_empty() {
+            self.emit(false, output);
+        }
+    }
+}
+
+/// Adds a prefix to the start of every line.
+#[derive(Debug, Clone)]
+pub struct Prefix(pub Vec<u8>);
+impl LineTransform for Prefix {
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        let mut prefixed = self.0.clone();
+        prefixed.extend_from_slice(&line);
+        Some(prefixed)
+    }
+}
+
+/// Adds a suffix to the end of every line, before its terminator.
+#[derive(Debug, Clone)]
+pub struct Suffix(pub Vec<u8>);
+impl LineTransform for Suffix {
+    fn line(&mut self, mut line: Vec<u8>) -> Option<Vec<u8>> {
+        line.extend_from_slice(&self.0);
+        Some(line)
+    }
+}
+
+/// Keeps only the lines that match a predicate.
+///
+/// Lines that are not valid UTF-8 are converted lossily before being passed to the predicate.
+pub struct Filter<F>(pub F);
+impl<F: FnMut(&str) -> bool + Send> LineTransform for Filter<F> {
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        if (self.0)(&String::from_utf8_lossy(&line)) {
+            Some(line)
+        } else {
+            None
+        }
+    }
+}
+impl<F> fmt::Debug for Filter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Filter").finish()
+    }
+}
+
+/// Replaces every line with the result of a function.
+///
+/// Lines that are not valid UTF-8 are converted lossily before being passed to the function.
+pub struct Map<F>(pub F);
+impl<F: FnMut(&str) -> String + Send> LineTransform for Map<F> {
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        Some((self.0)(&String::from_utf8_lossy(&line)).into_bytes())
+    }
+}
+impl<F> fmt::Debug for Map<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Map").finish()
+    }
+}
+
+/// Drops lines that are identical to the line before them, like `uniq`.
+#[derive(Debug, Clone, Default)]
+pub struct CollapseRepeats {
+    last: Option<Vec<u8>>,
+}
+impl CollapseRepeats {
+    /// Create a new collapsing transform.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl LineTransform for CollapseRepeats {
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        if self.last.as_ref() == Some(&line) {
+            return None;
+        }
+        self.last = Some(line.clone());
+        Some(line)
+    }
+}
+
+/// Masks secrets in every line.
+///
+/// Secrets can be given as literals, as regular expressions, or as the names of environment
+/// variables whose values are secret. Matching is done on whole lines, so a secret is masked even if
+/// the pipe read it in several pieces, but a secret that itself spans lines will not be found.
+/// Because of that, output is held back until the end of its line, and output that never ends a
+/// line is held in memory until the relay finishes, however long it grows.
+///
+/// Put this first in a pipeline, so that no other stage ever sees the secrets. Needs the `redact`
+/// feature.
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use stdio_override::transform::{Pipeline, Redact};
+///
+/// let redact = Redact::new().literal("hunter2")?.env_var("GITHUB_TOKEN")?.regex(r"ghp_[A-Za-z0-9]{36}")?;
+/// let pipeline = Pipeline::new().redact(redact).prefix("[ci] ");
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "redact")]
+#[derive(Clone)]
+pub struct Redact {
+    literals: Vec<Vec<u8>>,
+    patterns: Vec<String>,
+    regex: Option<Regex>,
+    mask: Vec<u8>,
+}
+#[cfg(feature = "redact")]
+impl Redact {
+    /// Create a redaction stage with no secrets, which masks with `[REDACTED]`.
+    pub fn new() -> Self {
+        Self { literals: Vec::new(), patterns: Vec::new(), regex: None, mask: b"[REDACTED]".to_vec() }
+    }
+    /// Replace secrets with this instead of `[REDACTED]`.
+    pub fn mask<M: Into<Vec<u8>>>(mut self, mask: M) -> Self {
+        self.mask = mask.into();
+        self
+    }
+    /// Mask every occurrence of the secret. This fails with [`io::ErrorKind::InvalidInput`] if the
+    /// secret is empty, or if the secrets together are too long to match.
+    pub fn literal<S: AsRef<[u8]>>(mut self, secret: S) -> io::Result<Self> {
+        let secret = secret.as_ref();
+        if secret.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "secrets can't be empty"));
+        }
+        self.literals.push(secret.to_vec());
+        self.regex = Some(self.compile().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?);
+        Ok(self)
+    }
+    /// Mask everything matching the regular expression. This fails with
+    /// [`io::ErrorKind::InvalidInput`] if the pattern is invalid or matches an empty string.
+    pub fn regex(mut self, pattern: &str) -> io::Result<Self> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        if Regex::new(pattern).map_err(invalid)?.is_match(b"") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} matches an empty string", pattern)));
+        }
+        self.patterns.push(pattern.to_owned());
+        self.regex = Some(self.compile().map_err(invalid)?);
+        Ok(self)
+    }
+    /// Mask the current value of the environment variable, if it is set and not empty.
+    ///
+    /// On Unix the value is matched byte for byte. On Windows it is matched as UTF-8, and this
+    /// fails with [`io::ErrorKind::InvalidData`] if it isn't valid Unicode.
+    pub fn env_var<K: AsRef<OsStr>>(self, name: K) -> io::Result<Self> {
+        let value = match env::var_os(name.as_ref()) {
+            Some(value) if !value.is_empty() => value,
+            _ => return Ok(self),
+        };
+        #[cfg(unix)]
+        let secret = std::os::unix::ffi::OsStrExt::as_bytes(&*value);
+        #[cfg(windows)]
+        let secret = value
+            .to_str()
+            .map(str::as_bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not valid Unicode", name.as_ref())))?;
+        self.literal(secret)
+    }
+    fn compile(&self) -> Result<Regex, regex::Error> {
+        // Longer literals go first so that a secret containing another is masked entirely.
+        let mut literals: Vec<&Vec<u8>> = self.literals.iter().collect();
+        literals.sort_by_key(|literal| std::cmp::Reverse(literal.len()));
+
+        let escaped = literals.into_iter().map(|literal| literal.iter().map(|b| format!("\\x{:02x}", b)).collect::<String>());
+        let patterns = self.patterns.iter().map(|pattern| format!("(?u:{})", pattern));
+        let alternatives: Vec<String> = escaped.chain(patterns).collect();
+        Regex::new(&format!("(?-u){}", alternatives.join("|")))
+    }
+}
+#[cfg(feature = "redact")]
+impl Default for Redact {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[cfg(feature = "redact")]
+impl fmt::Debug for Redact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The secrets themselves are left out, so that debug output can't leak them.
+        f.debug_struct("Redact")
+            .field("secrets", &(self.literals.len() + self.patterns.len()))
+            .field("mask", &String::from_utf8_lossy(&self.mask))
+            .finish()
+    }
+}
+#[cfg(feature = "redact")]
+impl LineTransform for Redact {
+    fn line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        match &self.regex {
+            Some(regex) => Some(regex.replace_all(&line, NoExpand(&self.mask)).into_owned()),
+            None => Some(line),
+        }
+    }
+}
+
+/// A sequence of transforms, applied in the order they were added.
+///
+/// A pipeline is itself a [`Transform`], so pipelines can be nested.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+impl Pipeline {
+    /// Create an empty pipeline, which passes everything through unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a transform to the end of the pipeline.
+    pub fn then<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.stages.push(Box::new(transform));
+        self
+    }
+    /// Add a line transform to the end of the pipeline.
+    pub fn then_lines<T: LineTransform + 'static>(self, transform: T) -> Self {
+        self.then(Lines::new(transform))
+    }
+    /// Prefix every line.
+    pub fn prefix<P: Into<Vec<u8>>>(self, prefix: P) -> Self {
+        self.then_lines(Prefix(prefix.into()))
+    }
+    /// Suffix every line.
+    pub fn suffix<S: Into<Vec<u8>>>(self, suffix: S) -> Self {
+        self.then_lines(Suffix(suffix.into()))
+    }
+    /// Keep only the lines for which the predicate returns `true`.
+    pub fn filter<F: FnMut(&str) -> bool + Send + 'static>(self, predicate: F) -> Self {
+        self.then_lines(Filter(predicate))
+    }
+    /// Replace every line with the result of the function.
+    pub fn map<F: FnMut(&str) -> String + Send + 'static>(self, f: F) -> Self {
+        self.then_lines(Map(f))
+    }
+    /// Drop lines that repeat the line before them.
+    pub fn collapse_repeats(self) -> Self {
+        self.then_lines(CollapseRepeats::new())
+    }
+    /// Mask secrets in every line.
+    #[cfg(feature = "redact")]
+    pub fn redact(self, redact: Redact) -> Self {
+        self.then_lines(redact)
+    }
+    /// Whether the pipeline has no stages.
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+impl Transform for Pipeline {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        run(&mut self.stages, input, output);
+    }
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        // Whatever a stage held back still has to go through every stage after it.
+        for i in 0..self.stages.len() {
+            let mut flushed = Vec::new();
+            self.stages[i].finish(&mut flushed);
+            run(&mut self.stages[i + 1..], &flushed, output);
+        }
+    }
+}
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pipeline").field("stages", &self.stages.len()).finish()
+    }
+}
+
+fn run(stages: &mut [Box<dyn Transform>], input: &[u8], output: &mut Vec<u8>) {
+    let mut current = Cow::Borrowed(input);
+    for stage in stages {
+        if current.is_empty() {
+            return;
+        }
+        let mut next = Vec::new();
+        stage.transform(&current, &mut next);
+        current = Cow::Owned(next);
+    }
+    output.extend_from_slice(&current);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(pipeline: &mut Pipeline, chunks: &[&str]) -> String {
+        let mut output = Vec::new();
+        for chunk in chunks {
+            pipeline.transform(chunk.as_bytes(), &mut output);
+        }
+        pipeline.finish(&mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_lines_across_chunks() {
+        let mut pipeline = Pipeline::new().prefix("[w] ").suffix(";");
+        let output = apply(&mut pipeline, &["ab", "c\r\nd", "ef\n", "tail"]);
+        assert_eq!("[w] abc;\r\n[w] def;\n[w] tail;", output);
+    }
+
+    #[test]
+    fn test_filter_map_collapse() {
+        let mut pipeline =
+            Pipeline::new().filter(|line| !line.starts_with("DEBUG")).map(|line| line.replace("/home/user", "~")).collapse_repeats();
+        let output = apply(&mut pipeline, &["DEBUG x\n/home/user/a\n/home/", "user/a\nDEBUG y\n/home/user/a\nb\n"]);
+        assert_eq!("~/a\nb\n", output);
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn test_redact_split_secret() -> io::Result<()> {
+        std::env::set_var("STDIO_OVERRIDE_TEST_SECRET", "s3cr3t");
+        let redact = Redact::new()
+            .literal("tok")?
+            .literal("token-1234")?
+            .env_var("STDIO_OVERRIDE_TEST_SECRET")?
+            .env_var("STDIO_OVERRIDE_TEST_UNSET")?
+            .regex(r"ghp_\w{4}")?
+            .mask("***");
+        let mut pipeline = Pipeline::new().redact(redact);
+        let output = apply(&mut pipeline, &["a token-", "1234 b s3c", "r3t\nghp_ab", "cd tok"]);
+        assert_eq!("a *** b ***\n*** ***", output);
+        let debug = format!("{:?}", Redact::new().literal("token-1234")?.regex(r"ghp_\w{4}")?);
+        assert_eq!(r#"Redact { secrets: 2, mask: "[REDACTED]" }"#, debug);
+
+        assert_eq!(io::ErrorKind::InvalidInput, Redact::new().literal("").unwrap_err().kind());
+        // Past the regex crate's size limit.
+        assert_eq!(io::ErrorKind::InvalidInput, Redact::new().literal(vec![b'x'; 1 << 19]).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput, Redact::new().regex("").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput, Redact::new().regex("x*").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput, Redact::new().regex("(").unwrap_err().kind());
+        Ok(())
+    }
+
+    #[cfg(all(feature = "redact", unix))]
+    #[test]
+    fn test_redact_non_utf8_env_var() -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        std::env::set_var("STDIO_OVERRIDE_TEST_BINARY_SECRET", OsStr::from_bytes(b"s\xffcret"));
+        let mut pipeline = Pipeline::new().redact(Redact::new().env_var("STDIO_OVERRIDE_TEST_BINARY_SECRET")?.mask("***"));
+        let mut output = Vec::new();
+        pipeline.transform(b"a s\xffcret and s\xef\xbf\xbdcret\n", &mut output);
+        assert_eq!(b"a *** and s\xef\xbf\xbdcret\n".to_vec(), output);
+        Ok(())
+    }
+}
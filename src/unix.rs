@@ -1,10 +1,13 @@
 use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
 
 use libc::c_int;
 use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 
+use crate::registry::Stream;
+
 pub(crate) use std::os::unix::io::{AsRawFd as AsRaw, IntoRawFd as IntoRaw, RawFd as Raw};
 
 pub(crate) fn as_raw(io: &impl AsRawFd) -> RawFd {
@@ -13,6 +16,9 @@ pub(crate) fn as_raw(io: &impl AsRawFd) -> RawFd {
 pub(crate) fn into_raw(io: impl IntoRawFd) -> RawFd {
     io.into_raw_fd()
 }
+pub(crate) unsafe fn file_from_raw(raw: RawFd) -> File {
+    File::from_raw_fd(raw)
+}
 
 pub(crate) fn override_stdin(io: RawFd, owned: bool) -> io::Result<File> {
     override_stdio(STDIN_FILENO, io, owned)
@@ -25,17 +31,66 @@ pub(crate) fn override_stderr(io: RawFd, owned: bool) -> io::Result<File> {
 }
 
 pub(crate) fn reset_stdin(old: RawFd) -> io::Result<()> {
-    set_stdio(STDIN_FILENO, old)
+    reset_stdio(STDIN_FILENO, old)
 }
 pub(crate) fn reset_stdout(old: RawFd) -> io::Result<()> {
-    set_stdio(STDOUT_FILENO, old)
+    reset_stdio(STDOUT_FILENO, old)
 }
 pub(crate) fn reset_stderr(old: RawFd) -> io::Result<()> {
-    set_stdio(STDERR_FILENO, old)
+    reset_stdio(STDERR_FILENO, old)
+}
+
+/// Put an original stream back, without closing the saved copy, which still belongs to its guard.
+pub(crate) fn restore(stream: Stream, original: RawFd) -> io::Result<()> {
+    let stdio = match stream {
+        Stream::Stdin => STDIN_FILENO,
+        Stream::Stdout => STDOUT_FILENO,
+        Stream::Stderr => STDERR_FILENO,
+    };
+    set_stdio(stdio, original)
+}
+
+/// Point an overridden stream at `/dev/null`, using only async-signal-safe calls.
+pub(crate) fn discard(stream: Stream) -> io::Result<()> {
+    let dev_null = io_res(unsafe { libc::open(b"/dev/null\0".as_ptr().cast(), libc::O_RDWR) })?;
+    let res = restore(stream, dev_null);
+    close(dev_null)?;
+    res
+}
+
+pub(crate) fn close(fd: RawFd) -> io::Result<()> {
+    io_res(unsafe { libc::close(fd) })?;
+    Ok(())
+}
+
+/// How many bytes can be read from the pipe without blocking.
+pub(crate) fn unread(pipe: &impl AsRawFd) -> io::Result<usize> {
+    let mut unread: c_int = 0;
+    io_res(unsafe { libc::ioctl(pipe.as_raw_fd(), libc::FIONREAD, &mut unread) })?;
+    Ok(unread as usize)
+}
+
+/// Write as much of `buf` as fits in the pipe without blocking, failing with
+/// [`io::ErrorKind::WouldBlock`] if none of it does.
+pub(crate) fn write_nonblocking(pipe: &impl AsRawFd, buf: &[u8]) -> io::Result<usize> {
+    let fd = pipe.as_raw_fd();
+    let flags = io_res(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    io_res(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+    let written = unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) };
+    let res = if written == -1 { Err(io::Error::last_os_error()) } else { Ok(written as usize) };
+    io_res(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) })?;
+    res
+}
+
+/// Flush every C stdio stream.
+pub(crate) fn flush_c_stdio() {
+    unsafe { libc::fflush(ptr::null_mut()) };
 }
 
 fn override_stdio(stdio: RawFd, other: RawFd, owned: bool) -> io::Result<File> {
-    let original = io_res(unsafe { libc::dup(stdio) })?;
+    // The saved original must not be inherited by children, or it would keep whatever it refers to
+    // open for as long as they run.
+    let original = io_res(unsafe { libc::fcntl(stdio, libc::F_DUPFD_CLOEXEC, 0) })?;
     set_stdio(stdio, other)?;
 
     if owned {
@@ -45,6 +100,13 @@ fn override_stdio(stdio: RawFd, other: RawFd, owned: bool) -> io::Result<File> {
     Ok(unsafe { File::from_raw_fd(original) })
 }
 
+fn reset_stdio(stdio: RawFd, old: RawFd) -> io::Result<()> {
+    set_stdio(stdio, old)?;
+    // The saved copy is no longer needed, and keeping it open would keep whatever it refers to
+    // open too, such as the write end of an outer override's pipe.
+    close(old)
+}
+
 fn set_stdio(stdio: RawFd, other: RawFd) -> io::Result<()> {
     io_res(unsafe { libc::dup2(other, stdio) })?;
     Ok(())
@@ -4,10 +4,12 @@ use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle, RawHandle}
 use std::ptr;
 
 use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+use winapi::um::fileapi::WriteFile;
 use winapi::um::handleapi::{CloseHandle, DuplicateHandle, GetHandleInformation, INVALID_HANDLE_VALUE};
+use winapi::um::namedpipeapi::{PeekNamedPipe, SetNamedPipeHandleState};
 use winapi::um::processenv::{GetStdHandle, SetStdHandle};
 use winapi::um::processthreadsapi::GetCurrentProcess;
-use winapi::um::winbase::HANDLE_FLAG_INHERIT;
+use winapi::um::winbase::{HANDLE_FLAG_INHERIT, PIPE_NOWAIT, PIPE_WAIT};
 use winapi::um::winbase::{STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE};
 use winapi::um::winnt::DUPLICATE_SAME_ACCESS;
 
@@ -19,6 +21,9 @@ pub(crate) fn as_raw(io: &impl AsRawHandle) -> RawHandle {
 pub(crate) fn into_raw(io: impl IntoRawHandle) -> RawHandle {
     io.into_raw_handle()
 }
+pub(crate) unsafe fn file_from_raw(raw: RawHandle) -> File {
+    File::from_raw_handle(raw)
+}
 
 pub(crate) fn override_stdin(io: RawHandle, owned: bool) -> io::Result<File> {
     override_stdio(STD_INPUT_HANDLE, io, owned)
@@ -85,6 +90,37 @@ fn reset_stdio(stdio: DWORD, other: RawHandle) -> io::Result<()> {
     Ok(())
 }
 
+/// How many bytes can be read from the pipe without blocking.
+pub(crate) fn unread(pipe: &impl AsRawHandle) -> io::Result<usize> {
+    let mut unread: DWORD = 0;
+    io_res(unsafe { PeekNamedPipe(pipe.as_raw_handle().cast(), ptr::null_mut(), 0, ptr::null_mut(), &mut unread, ptr::null_mut()) })?;
+    Ok(unread as usize)
+}
+
+/// Write as much of `buf` as fits in the pipe without blocking, failing with
+/// [`io::ErrorKind::WouldBlock`] if none of it does.
+pub(crate) fn write_nonblocking(pipe: &impl AsRawHandle, buf: &[u8]) -> io::Result<usize> {
+    let handle = pipe.as_raw_handle().cast();
+    let mut mode = PIPE_NOWAIT;
+    io_res(unsafe { SetNamedPipeHandleState(handle, &mut mode, ptr::null_mut(), ptr::null_mut()) })?;
+    let mut written: DWORD = 0;
+    let len = buf.len().min(DWORD::MAX as usize) as DWORD;
+    let res = io_res(unsafe { WriteFile(handle, buf.as_ptr().cast(), len, &mut written, ptr::null_mut()) });
+    mode = PIPE_WAIT;
+    io_res(unsafe { SetNamedPipeHandleState(handle, &mut mode, ptr::null_mut(), ptr::null_mut()) })?;
+    res?;
+    match written {
+        // A full pipe in non-blocking mode takes nothing, and still succeeds.
+        0 if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+        written => Ok(written as usize),
+    }
+}
+
+/// Flush every C stdio stream.
+pub(crate) fn flush_c_stdio() {
+    unsafe { libc::fflush(ptr::null_mut()) };
+}
+
 fn io_res(res: BOOL) -> io::Result<()> {
     if res == 0 {
         Err(io::Error::last_os_error())
@@ -0,0 +1,57 @@
+#![cfg(feature = "macros")]
+
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int};
+use std::panic;
+use std::thread;
+
+use stdio_override::{macro_support, Capture};
+
+extern "C" {
+    fn printf(format: *const c_char, ...) -> c_int;
+}
+
+#[stdio_override::capture(stdout = "to stdout\n", stderr = "to stderr\n")]
+fn test_asserts_output() {
+    println!("to stdout");
+    eprintln!("to stderr");
+}
+
+#[stdio_override::capture]
+fn test_live_capture(out: &Capture) -> io::Result<()> {
+    write!(io::stdout(), "so far")?;
+    io::stdout().flush()?;
+    while out.contents().stdout.is_empty() {
+        thread::yield_now();
+    }
+    assert_eq!("so far", out.contents().stdout);
+    Ok(())
+}
+
+#[stdio_override::capture(stdout = "from C\n")]
+fn test_flushes_c_stdio() {
+    unsafe { printf(b"from C\n\0".as_ptr().cast()) };
+}
+
+#[stdio_override::capture(stdout = "not checked")]
+#[should_panic(expected = "the body panicked")]
+fn test_panicking_body() {
+    writeln!(io::stdout(), "printed before panicking").unwrap();
+    panic!("the body panicked");
+}
+
+#[test]
+fn test_panic_replays_output() -> io::Result<()> {
+    let outer = Capture::both()?;
+    let res = panic::catch_unwind(|| {
+        let _capture = macro_support::start();
+        writeln!(io::stdout(), "to stdout").unwrap();
+        writeln!(io::stderr(), "to stderr").unwrap();
+        panic::resume_unwind(Box::new("the body panicked"));
+    });
+    assert!(res.is_err());
+    let captured = outer.finish()?;
+    assert_eq!("to stdout\n", captured.stdout);
+    assert_eq!("to stderr\n", captured.stderr);
+    Ok(())
+}